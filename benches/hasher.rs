@@ -0,0 +1,53 @@
+//! Compares throughput of hashing the payload in a single write, where quads
+//! are hashed in batches through the multi-lane kernel, with writes of a
+//! single quad that are hashed one by one. Run with `cargo bench` without the
+//! `parallel` feature, so that both use a single thread. Bench uses the
+//! release profile, which optimizes for size, same as the shipped builds.
+
+use fr32_sha2_256_trunc254_padded_binary_tree_multihash::PieceHasher;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const PAYLOAD_SIZE: usize = 127 << 18;
+const RUNS: usize = 5;
+
+// Returns the fastest of the runs.
+fn measure<F: FnMut()>(mut run: F) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let throughput = PAYLOAD_SIZE as f64 / elapsed.as_secs_f64() / (1 << 20) as f64;
+    println!("{:<12} {:>10.2?} {:>8.1} MiB/s", name, elapsed, throughput);
+}
+
+fn main() {
+    let payload: Vec<u8> = (0..PAYLOAD_SIZE).map(|i| (i * 7 + 3) as u8).collect();
+
+    let quads = measure(|| {
+        let mut hasher = PieceHasher::new();
+        for quad in payload.chunks(127) {
+            hasher.try_update(quad).unwrap();
+        }
+        black_box(hasher.piece());
+    });
+    let batched = measure(|| {
+        let mut hasher = PieceHasher::new();
+        hasher.try_update(&payload).unwrap();
+        black_box(hasher.piece());
+    });
+
+    report("quads", quads);
+    report("batched", batched);
+    println!(
+        "speedup      {:>10.2}x",
+        quads.as_secs_f64() / batched.as_secs_f64()
+    );
+}
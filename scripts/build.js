@@ -7,7 +7,9 @@ const baseURL = new URL("../", import.meta.url)
 const main = async () => {
   // First build for the web, because generated wasm is what we actually want to
   // use. One generated later seems to produce different wasm.
-  execSync("wasm-pack build --release --target web -d ./gen --out-name wasm")
+  execSync(
+    "wasm-pack build --release --target web -d ./gen --out-name wasm -- --features wasm"
+  )
 
   // await moveFile("./gen/wasm_bg.wasm", "./gen/bytecode.wasm")
 
@@ -103,7 +105,7 @@ const copyFile = (from, to) =>
   FS.copyFile(new URL(from, baseURL), new URL(to, baseURL))
 
 const build = async () => {
-  execSync("wasm-pack build")
+  execSync("wasm-pack build -- --features wasm")
 }
 
 main()
@@ -0,0 +1,231 @@
+//! Spot checks of stored pieces. Auditor derives pseudo-random leaf
+//! challenges from a seed and the piece, storage node answers them with the
+//! challenged leaves and their inclusion proofs, and auditor verifies the
+//! responses against the piece root. Only leaves holding payload bytes are
+//! challenged, as the zero padding can be derived without storing anything.
+
+use crate::constant::{BITS_PER_BYTE, IN_BITS_FR};
+use crate::error::PieceHasherError;
+use crate::hasher::PieceHasher;
+use crate::piece::Piece;
+use crate::piece_tree::PieceTree;
+use crate::proof::ProofData;
+use crate::tree::MerkleTreeNode;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::io;
+
+/// Challenged leaf along with its inclusion proof.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Response {
+    leaf: MerkleTreeNode,
+    proof: ProofData,
+}
+
+impl Response {
+    pub fn new(leaf: MerkleTreeNode, proof: ProofData) -> Self {
+        Response { leaf, proof }
+    }
+
+    /// Index of the challenged leaf.
+    pub fn index(&self) -> u64 {
+        self.proof.index()
+    }
+    pub fn leaf(&self) -> &MerkleTreeNode {
+        &self.leaf
+    }
+    pub fn proof(&self) -> &ProofData {
+        &self.proof
+    }
+}
+
+/// Number of leaves holding payload bytes, where every leaf holds 254 bits.
+pub fn payload_leaves(piece: &Piece) -> u64 {
+    (piece.payload_size() * BITS_PER_BYTE as u64).div_ceil(IN_BITS_FR as u64)
+}
+
+/// Derives `count` leaf indices to challenge from the seed and the piece.
+/// Same seed and piece always produce the same challenges and leaves are
+/// sampled independently, so they may repeat. Pieces without payload have
+/// nothing to challenge.
+pub fn challenges(seed: &[u8], piece: &Piece, count: usize) -> Vec<u64> {
+    let leaves = payload_leaves(piece);
+    if leaves == 0 {
+        return vec![];
+    }
+
+    // Candidates above the largest multiple of `leaves` are skipped so that
+    // every leaf is equally likely.
+    let limit = u64::MAX - u64::MAX % leaves;
+    let multihash = piece.multihash().to_bytes();
+    let mut indices = Vec::with_capacity(count);
+    let mut counter = 0u64;
+    while indices.len() < count {
+        let digest = Sha256::new()
+            .chain(seed)
+            .chain(&multihash)
+            .chain(counter.to_le_bytes())
+            .finalize();
+        counter += 1;
+
+        let candidate = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        if candidate < limit {
+            indices.push(candidate % leaves);
+        }
+    }
+    indices
+}
+
+/// Responds to the challenges from the retained tree of the piece.
+pub fn respond(tree: &PieceTree, challenges: &[u64]) -> Result<Vec<Response>, PieceHasherError> {
+    challenges
+        .iter()
+        .map(|&index| {
+            let leaf = tree
+                .node(0, index)
+                .ok_or(PieceHasherError::NodeOutOfRange { level: 0, index })?;
+            Ok(Response::new(leaf, tree.prove(0, index)?))
+        })
+        .collect()
+}
+
+/// Responds to the challenges by hashing the stored payload from the reader,
+/// which does not require the tree to be retained.
+pub fn respond_reader<R: io::Read>(mut reader: R, challenges: &[u64]) -> io::Result<Vec<Response>> {
+    let targets: Vec<_> = challenges.iter().map(|&index| (0, index)).collect();
+    let mut hasher = PieceHasher::with_proofs(&targets);
+    io::copy(&mut reader, &mut hasher)?;
+    let (_, proofs) = hasher.proofs()?;
+    Ok(proofs
+        .into_iter()
+        .map(|(leaf, proof)| Response::new(leaf, proof))
+        .collect())
+}
+
+/// Checks that responses answer the challenges derived from the seed and
+/// the piece, in the same order, and that every leaf is included in it.
+pub fn verify(
+    seed: &[u8],
+    piece: &Piece,
+    count: usize,
+    responses: &[Response],
+) -> Result<(), PieceHasherError> {
+    let challenges = challenges(seed, piece, count);
+    if responses.len() != challenges.len() {
+        return Err(PieceHasherError::InvalidProof(format!(
+            "Expected {} responses, got {}",
+            challenges.len(),
+            responses.len()
+        )));
+    }
+
+    for (index, response) in challenges.iter().zip(responses.iter()) {
+        if response.index() != *index {
+            return Err(PieceHasherError::InvalidProof(format!(
+                "Expected response for leaf {}, got leaf {}",
+                index,
+                response.index()
+            )));
+        }
+        // Shorter path would prove a node above the leaves.
+        if response.proof.depth() != piece.height() {
+            return Err(PieceHasherError::InvalidProof(format!(
+                "Expected path of {} nodes, got {}",
+                piece.height(),
+                response.proof.depth()
+            )));
+        }
+        response.proof.verify(&piece.root(), &response.leaf, 0)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{challenges, payload_leaves, respond, respond_reader, verify, Response};
+    use crate::error::PieceHasherError;
+    use crate::hasher::PieceHasher;
+    use crate::piece::Piece;
+    use crate::piece_tree::tests::{payload, tree};
+    use crate::proof::ProofData;
+
+    #[test]
+    fn test_challenges() {
+        let (piece, _) = tree(127 * 3 + 40);
+        // Three quads and 40 bytes of the fourth one spanning two leaves.
+        assert_eq!(payload_leaves(&piece), 14);
+
+        let indices = challenges(b"seed", &piece, 100);
+        assert_eq!(indices.len(), 100);
+        assert!(indices.iter().all(|index| *index < 14));
+        assert!((0..14).all(|leaf| indices.contains(&leaf)));
+        assert_eq!(indices, challenges(b"seed", &piece, 100));
+        assert_eq!(indices[..10], challenges(b"seed", &piece, 10)[..]);
+        assert_ne!(indices, challenges(b"other", &piece, 100));
+
+        let empty = PieceHasher::new().piece();
+        assert_eq!(payload_leaves(&empty), 0);
+        assert!(challenges(b"seed", &empty, 5).is_empty());
+        assert_eq!(payload_leaves(&Piece::new(10)), 1 << 10);
+    }
+
+    #[test]
+    fn test_respond() {
+        for size in [127, 127 * 5 + 3, 127 * 2048 + 100] {
+            let bytes = payload(size);
+            let (piece, tree) = tree(size);
+
+            let indices = challenges(b"audit", &piece, 16);
+            let responses = respond(&tree, &indices).unwrap();
+            assert_eq!(
+                respond_reader(bytes.as_slice(), &indices).unwrap(),
+                responses
+            );
+            verify(b"audit", &piece, 16, &responses).unwrap();
+
+            assert!(verify(b"other", &piece, 16, &responses).is_err());
+            assert!(verify(b"audit", &piece, 15, &responses).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        let bytes = payload(127 * 8);
+        let (piece, tree) = tree(127 * 8);
+        let indices = challenges(b"seed", &piece, 4);
+        let responses = respond(&tree, &indices).unwrap();
+
+        // Data that differs from the piece does not verify.
+        let mut other = bytes.clone();
+        other[0] ^= 1;
+        let forged = respond_reader(other.as_slice(), &indices).unwrap();
+        assert!(verify(b"seed", &piece, 4, &forged).is_err());
+
+        // Responses must be in the order of the challenges.
+        let mut reordered = responses.clone();
+        reordered.swap(0, 3);
+        if indices[0] != indices[3] {
+            assert!(verify(b"seed", &piece, 4, &reordered).is_err());
+        }
+
+        // Proof of a node above the leaves is rejected.
+        let mut shallow = responses.clone();
+        let node = tree.node(1, indices[0] / 2).unwrap();
+        let path = tree.prove(1, indices[0] / 2).unwrap().path().to_vec();
+        shallow[0] = Response::new(node, ProofData::new(indices[0], path));
+        assert_eq!(
+            verify(b"seed", &piece, 4, &shallow),
+            Err(PieceHasherError::InvalidProof(String::from(
+                "Expected path of 5 nodes, got 4"
+            )))
+        );
+
+        assert_eq!(
+            respond(&tree, &[1 << 20]),
+            Err(PieceHasherError::NodeOutOfRange {
+                level: 0,
+                index: 1 << 20
+            })
+        );
+    }
+}
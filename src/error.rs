@@ -0,0 +1,68 @@
+use std::{fmt, io};
+
+/// Errors produced by the piece hasher and related utilities.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PieceHasherError {
+    /// Writing more bytes would exceed `MAX_PAYLOAD_SIZE`.
+    PayloadTooLarge,
+    /// Piece does not describe a valid tree.
+    InvalidPiece(String),
+    /// Target buffer can not fit the output.
+    BufferTooSmall { required: usize, available: usize },
+    /// Requested tree level is outside of the supported range.
+    LevelOutOfRange { level: usize, max: usize },
+    /// Serialized hasher state is corrupted or inconsistent.
+    InvalidCheckpoint(String),
+    /// Hashers can not be merged because parts are not aligned.
+    InvalidMerge(String),
+    /// Tree has no node at the given level and index.
+    NodeOutOfRange { level: usize, index: u64 },
+    /// Proof is malformed or does not match the root.
+    InvalidProof(String),
+    /// Byte range is empty or is outside of the payload.
+    InvalidRange { start: u64, end: u64 },
+    /// Stored tree is malformed or does not match the piece.
+    InvalidTree(String),
+}
+
+impl fmt::Display for PieceHasherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PieceHasherError::PayloadTooLarge => write!(f, "Payload size exceeded"),
+            PieceHasherError::InvalidPiece(reason) => write!(f, "Invalid piece: {}", reason),
+            PieceHasherError::BufferTooSmall {
+                required,
+                available,
+            } => write!(
+                f,
+                "Buffer too small, {} bytes required but only {} available",
+                required, available
+            ),
+            PieceHasherError::LevelOutOfRange { max, .. } => write!(
+                f,
+                "Only levels between 0 and {} inclusive are available",
+                max
+            ),
+            PieceHasherError::InvalidCheckpoint(reason) => {
+                write!(f, "Invalid checkpoint: {}", reason)
+            }
+            PieceHasherError::InvalidMerge(reason) => write!(f, "Invalid merge: {}", reason),
+            PieceHasherError::NodeOutOfRange { level, index } => {
+                write!(f, "No node at index {} of level {}", index, level)
+            }
+            PieceHasherError::InvalidProof(reason) => write!(f, "Invalid proof: {}", reason),
+            PieceHasherError::InvalidRange { start, end } => {
+                write!(f, "Invalid byte range {}..{}", start, end)
+            }
+            PieceHasherError::InvalidTree(reason) => write!(f, "Invalid tree: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PieceHasherError {}
+
+impl From<PieceHasherError> for io::Error {
+    fn from(error: PieceHasherError) -> Self {
+        io::Error::other(error)
+    }
+}
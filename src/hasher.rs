@@ -1,19 +1,31 @@
 use crate::constant::{IN_BITS_FR, IN_BYTES_PER_QUAD, NODE_SIZE, OUT_BITS_FR, OUT_BYTES_PER_QUAD};
+use crate::error::PieceHasherError;
+#[cfg(feature = "parallel")]
+use crate::parallel;
 use crate::piece::Piece;
-use crate::tree::{compute_node, truncated_hash, MerkleTreeNode};
-use crate::util::{from_height, required_zero_padding};
-use crate::{varint_estimate, zero_comm};
-use cid;
+use crate::proof::{ProofCollector, ProofData};
+use crate::size::required_zero_padding;
+use crate::tree::{compute_layer, compute_node, hash_blocks, MerkleTreeNode, BLOCK_SIZE, LANES};
+use crate::util::{from_height, varint_estimate};
+use crate::zero_comm;
 use core::primitive::u64;
-use js_sys::{Error, Uint8Array};
 use multihash::Multihash;
 use multihash_derive::Hasher;
+use std::cmp::max;
 use std::convert::{TryFrom, TryInto};
-use unsigned_varint;
+use std::io;
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
 // Fits for 32PiB of data
-const MAX_HEIGHT: u8 = 50; //u8::MAX;
+pub(crate) const MAX_HEIGHT: u8 = 50; //u8::MAX;
+
+/// Largest number of quads hashed as a single batch, which bounds the stack
+/// space used for the nodes of the batch (~24KiB).
+pub(crate) const BATCH_QUADS: usize = 256;
+
+/// Smallest batch worth hashing, which fills all the lanes of the kernel.
+const MIN_BATCH_QUADS: u64 = (LANES / 2) as u64;
 
 // Multihash code
 pub const CODE: u64 = 0x1011;
@@ -23,12 +35,24 @@ pub const HEIGHT_SIZE: usize = 1; // Height is stored in a single byte
 pub const ROOT_SIZE: usize = NODE_SIZE; // Size of the merkle tree root
 pub const CODE_SIZE: usize = varint_estimate(CODE); // Size of the multihash code
 
-const RAW: usize = 0x55;
+pub(crate) const RAW: usize = 0x55;
+// Codec and multihash code of the PieceCIDv1 (CommP) links.
+pub(crate) const FIL_COMMITMENT_UNSEALED: u64 = 0xf101;
+pub(crate) const SHA2_256_TRUNC254_PADDED: u64 = 0x1012;
 
 pub const MAX_MULTIHASH_SIZE: usize = HEIGHT_SIZE + MAX_PADDING_SIZE + NODE_SIZE;
-type Layer = Vec<MerkleTreeNode>;
-type Layers = Vec<Layer>;
-type QuadBuffer = [u8; IN_BYTES_PER_QUAD];
+// Version of the binary checkpoint format produced by `PieceHasher::checkpoint`.
+const CHECKPOINT_VERSION: u8 = 1;
+// Version byte + bytes written (u64) + buffer offset (u8)
+const CHECKPOINT_HEADER_SIZE: usize = 1 + 8 + 1;
+
+/**
+ * Pending nodes of the tree where `frontier[0]` corresponds to the nodes
+ * derived from leaf pairs. Node pairs are combined into the next layer as soon
+ * as the right sibling is known, so each layer holds at most one node.
+ */
+type Frontier = [Option<MerkleTreeNode>; MAX_HEIGHT as usize];
+pub(crate) type QuadBuffer = [u8; IN_BYTES_PER_QUAD];
 
 /**
  * Max payload is determined by the maximum height of the tree, which is limited
@@ -38,12 +62,53 @@ type QuadBuffer = [u8; IN_BYTES_PER_QUAD];
 pub const MAX_PAYLOAD_SIZE: u64 =
     from_height(MAX_HEIGHT as u32) * IN_BITS_FR as u64 / OUT_BITS_FR as u64;
 
-#[wasm_bindgen]
+/// Receives nodes of the piece tree as they are computed, along with their
+/// tree level (where leaves are at level 0) and index within that level.
+pub trait NodeObserver {
+    fn observe(&mut self, level: usize, index: u64, node: &MerkleTreeNode);
+}
+
+impl<F: FnMut(usize, u64, &MerkleTreeNode)> NodeObserver for F {
+    fn observe(&mut self, level: usize, index: u64, node: &MerkleTreeNode) {
+        self(level, index, node)
+    }
+}
+
+/// Bound of the observers, which have to be `Send + Sync` for the hasher to be
+/// thread safe, except in wasm where JS values can not be shared across
+/// threads and module runs in one thread.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSendSync: Send + Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + Sync> MaybeSendSync for T {}
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSendSync {}
+#[cfg(target_arch = "wasm32")]
+impl<T> MaybeSendSync for T {}
+
+#[cfg(not(target_arch = "wasm32"))]
+type BoxedObserver = Box<dyn NodeObserver + Send + Sync>;
+#[cfg(target_arch = "wasm32")]
+type BoxedObserver = Box<dyn NodeObserver>;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub struct PieceHasher {
     pub(crate) bytes_written: u64,
-    buffer: QuadBuffer,
-    offset: usize,
-    layers: Layers,
+    pub(crate) buffer: QuadBuffer,
+    pub(crate) offset: usize,
+    frontier: Frontier,
+    // Number of frontier layers in use.
+    height: usize,
+    // All the nodes computed so far by tree level (leaves first) when the
+    // tree is retained.
+    pub(crate) retained: Option<Vec<Vec<MerkleTreeNode>>>,
+    // Nodes required for inclusion proofs of the target nodes.
+    collector: Option<Box<ProofCollector>>,
+    // Observer notified of every computed node.
+    observer: Option<BoxedObserver>,
+    // Set when `Hasher::update` exceeded `MAX_PAYLOAD_SIZE`, which fails all
+    // the following updates until the hasher is reset.
+    overflowed: bool,
 
     digest: [u8; MAX_MULTIHASH_SIZE],
 }
@@ -54,19 +119,24 @@ impl PieceHasher {
             bytes_written: 0,
             buffer: [0; IN_BYTES_PER_QUAD],
             offset: 0,
-            layers: vec![Vec::new()],
+            frontier: [None; MAX_HEIGHT as usize],
+            height: 1,
+            retained: None,
+            collector: None,
+            observer: None,
+            overflowed: false,
             digest: [0; MAX_MULTIHASH_SIZE],
         }
     }
 
-    pub fn try_update(&mut self, bytes: &[u8]) -> Result<(), Error> {
-        let leaves = &mut self.layers[0];
+    pub fn try_update(&mut self, bytes: &[u8]) -> Result<(), PieceHasherError> {
         let length = bytes.len();
+        if self.overflowed || self.bytes_written + length as u64 > MAX_PAYLOAD_SIZE {
+            Result::Err(PieceHasherError::PayloadTooLarge)
+        }
         // If we got no bytes there is nothing to do here
-        if length == 0 {
-            return Result::Ok(());
-        } else if self.bytes_written + length as u64 > MAX_PAYLOAD_SIZE as u64 {
-            return Result::Err(Error::new("Payload size exceeded"));
+        else if length == 0 {
+            Result::Ok(())
         }
         // If we do not have enough bytes to form a quad, just add append new bytes
         // to the buffer and return.
@@ -74,45 +144,570 @@ impl PieceHasher {
             self.buffer[self.offset..self.offset + length].copy_from_slice(bytes);
             self.offset += length;
             self.bytes_written += length as u64;
-            return Result::Ok(());
+            Result::Ok(())
         } else {
             let bytes_required = self.buffer.len() - self.offset;
             self.buffer[self.offset..].copy_from_slice(&bytes[..bytes_required]);
-            read_quad(&self.buffer, leaves);
-            // leaves.append(&mut split(pad(&self.buffer)));
+            let quad = self.buffer;
+            self.push_quad(&quad);
             let mut read_offset = bytes_required;
+            let mut quads = (self.bytes_written + bytes_required as u64) / IN_BYTES_PER_QUAD as u64;
 
             while read_offset + IN_BYTES_PER_QUAD < length {
+                // Last complete quad stays buffered.
+                let available = ((length - read_offset - 1) / IN_BYTES_PER_QUAD) as u64;
+
+                // Hash large aligned subtrees across threads and add their
+                // roots to the frontier as if they were hashed one by one.
+                // Nodes inside of those subtrees are not observed so they are
+                // hashed in batches when tree is retained or proofs are
+                // collected.
+                #[cfg(feature = "parallel")]
+                {
+                    let width = if self.observing() {
+                        None
+                    } else {
+                        parallel::subtree_width(quads, available)
+                    };
+                    if let Some(width) = width {
+                        let size = width as usize * IN_BYTES_PER_QUAD;
+                        let root = parallel::hash_subtree(&bytes[read_offset..read_offset + size]);
+                        // Each quad produces a node in the second layer.
+                        self.push_at(root, width.trailing_zeros() as usize + 1);
+                        read_offset += size;
+                        quads += width;
+                        continue;
+                    }
+                }
+
+                // Hash smaller aligned subtrees in batches so that the
+                // multi-lane kernel hashes many nodes at once.
+                if let Some(width) = batch_width(quads, available) {
+                    let size = width as usize * IN_BYTES_PER_QUAD;
+                    self.push_batch(&bytes[read_offset..read_offset + size]);
+                    read_offset += size;
+                    quads += width;
+                    continue;
+                }
+
                 let quad = &bytes[read_offset..read_offset + IN_BYTES_PER_QUAD];
-                read_quad(quad.try_into().unwrap(), leaves);
+                self.push_quad(quad.try_into().unwrap());
                 read_offset += IN_BYTES_PER_QUAD;
+                quads += 1;
             }
 
             self.buffer[..length - read_offset].copy_from_slice(&bytes[read_offset..]);
             self.offset = length - read_offset;
             self.bytes_written += length as u64;
 
-            prune(&mut self.layers);
-            return Result::Ok(());
+            Result::Ok(())
         }
     }
 
+    /// Creates a hasher that collects inclusion proofs for the nodes at the
+    /// given `(level, index)` positions while hashing, without retaining the
+    /// tree. Proofs can be obtained via `PieceHasher::proofs`.
+    pub fn with_proofs(targets: &[(usize, u64)]) -> Self {
+        let mut hasher = PieceHasher::new();
+        hasher.collector = Some(Box::new(ProofCollector::new(targets, MAX_HEIGHT as usize)));
+        hasher
+    }
+
+    /// Creates a hasher that reports every node of the tree to the given
+    /// observer as it is computed. Nodes of each level are reported in order
+    /// as the payload is written. Nodes that depend on the partially written
+    /// quad or the zero padding change with further writes, so they are only
+    /// reported by `PieceHasher::finish`.
+    pub fn with_observer<O: NodeObserver + MaybeSendSync + 'static>(observer: O) -> Self {
+        let mut hasher = PieceHasher::new();
+        hasher.observer = Some(Box::new(observer));
+        hasher
+    }
+
+    /// Returns the piece for the bytes written so far.
+    pub fn piece(&self) -> Piece {
+        let (root, height) = self.root();
+        Piece::from_parts(root, height, required_zero_padding(self.bytes_written))
+    }
+
+    /// Same as `piece` but also reports the nodes that depend on the
+    /// partially written quad or the zero padding to the observer. It is
+    /// meant to be called once all the bytes are written, as those nodes
+    /// change with further writes. Observer is dropped afterwards so that
+    /// every node is reported exactly once.
+    pub fn finish(&mut self) -> Piece {
+        let (root, height) = match self.observer.take() {
+            Some(mut observer) => {
+                self.root_with(|level, index, node| observer.observe(level, index, node))
+            }
+            None => self.root(),
+        };
+        Piece::from_parts(root, height, required_zero_padding(self.bytes_written))
+    }
+
+    /// Returns the piece for the bytes written so far along with the target
+    /// nodes and their inclusion proofs in the order of targets passed to
+    /// `PieceHasher::with_proofs`. Fails if any of the targets is outside of
+    /// the piece tree.
+    pub fn proofs(&self) -> Result<(Piece, Vec<(MerkleTreeNode, ProofData)>), PieceHasherError> {
+        let mut collector = match &self.collector {
+            Some(collector) => collector.as_ref().clone(),
+            None => return Ok((self.piece(), vec![])),
+        };
+
+        let (root, height) =
+            self.root_with(|level, index, node| collector.observe(level, index, node));
+        let piece = Piece::from_parts(root, height, required_zero_padding(self.bytes_written));
+
+        // Every node in the bottom frontier layer covers two leaves.
+        let tail = if self.offset > 0 || self.bytes_written == 0 {
+            2
+        } else {
+            0
+        };
+        let width = (self.width() + tail) * 2;
+
+        Ok((piece, collector.proofs(height, width)?))
+    }
+
+    pub fn multihash(&self) -> Multihash {
+        self.piece().multihash()
+    }
+
+    pub fn link(&self) -> cid::Cid {
+        self.piece().link()
+    }
+
+    /// Reads all the bytes from the given reader and returns resulting piece.
+    pub fn hash_reader<R: io::Read>(mut reader: R) -> io::Result<Piece> {
+        let mut hasher = PieceHasher::new();
+        io::copy(&mut reader, &mut hasher)?;
+        Ok(hasher.piece())
+    }
+
     /// Returns number of bytes required to store the raw digest.
-    // pub fn digestSize() {
-    //     let paddingLength = required_zero_padding(self.bytes_written);
-    //     unsigned_varint::codec::u64::
-    // }
+    pub fn digest_size(&self) -> usize {
+        let padding = required_zero_padding(self.bytes_written);
 
-    /// Returns number of bytes required to store the multihash digest.
-    pub fn multihashSize() {}
+        varint_estimate(padding) + HEIGHT_SIZE + ROOT_SIZE
+    }
+
+    /// Returns number of bytes required to store the multihash.
+    pub fn multihash_size(&self) -> usize {
+        let hash_size = self.digest_size();
 
-    pub fn multihash(&mut self) -> Multihash {
-        let bytes = self.finalize();
-        Multihash::wrap(CODE, bytes).unwrap()
+        CODE_SIZE + varint_estimate(hash_size as u64) + hash_size
     }
 
-    pub fn link(&mut self) -> cid::Cid {
-        cid::Cid::new_v1(RAW as u64, self.multihash())
+    /// Writes the multihash (or just the digest when `use_prefix` is `false`)
+    /// into the `target` at the given `offset` and returns number of bytes
+    /// written.
+    pub fn digest_into(
+        &self,
+        target: &mut [u8],
+        offset: usize,
+        use_prefix: bool,
+    ) -> Result<usize, PieceHasherError> {
+        let hash = self.multihash();
+        let bytes = if use_prefix {
+            hash.to_bytes()
+        } else {
+            hash.digest().to_vec()
+        };
+
+        let available = target.len().saturating_sub(offset);
+        if available < bytes.len() {
+            return Err(PieceHasherError::BufferTooSmall {
+                required: bytes.len(),
+                available,
+            });
+        }
+
+        target[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    /// Serializes the full hasher state so that hashing can be resumed
+    /// later (possibly in another process) via `PieceHasher::restore`.
+    /// Nodes collected for proofs are not included.
+    ///
+    /// Layout (version 1):
+    ///
+    /// ```text
+    /// version       u8
+    /// bytes_written u64 (little endian)
+    /// offset        u8
+    /// buffer        [u8; offset]
+    /// layer count   u8
+    /// layers        (node count u8, [u8; 32] * node count) * layer count
+    /// ```
+    pub fn checkpoint(&self) -> Vec<u8> {
+        let layers = &self.frontier[..self.height];
+        let nodes = layers.iter().filter(|node| node.is_some()).count();
+        let mut bytes = Vec::with_capacity(
+            CHECKPOINT_HEADER_SIZE + self.offset + 1 + layers.len() + nodes * NODE_SIZE,
+        );
+
+        bytes.push(CHECKPOINT_VERSION);
+        bytes.extend_from_slice(&self.bytes_written.to_le_bytes());
+        bytes.push(self.offset as u8);
+        bytes.extend_from_slice(&self.buffer[..self.offset]);
+
+        bytes.push(layers.len() as u8);
+        for layer in layers.iter() {
+            match layer {
+                Some(node) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&node.0);
+                }
+                None => bytes.push(0),
+            }
+        }
+
+        bytes
+    }
+
+    /// Restores a hasher from the bytes produced by `PieceHasher::checkpoint`.
+    /// Snapshots that are truncated, corrupted or describe a state that could
+    /// not have been produced by writing `bytes_written` bytes are rejected.
+    pub fn restore(bytes: &[u8]) -> Result<Self, PieceHasherError> {
+        if bytes.len() < CHECKPOINT_HEADER_SIZE {
+            return Err(invalid_checkpoint("Checkpoint is truncated"));
+        }
+
+        let version = bytes[0];
+        if version != CHECKPOINT_VERSION {
+            return Err(PieceHasherError::InvalidCheckpoint(format!(
+                "Unsupported checkpoint version {}",
+                version
+            )));
+        }
+
+        let bytes_written = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+        if bytes_written > MAX_PAYLOAD_SIZE {
+            return Err(PieceHasherError::PayloadTooLarge);
+        }
+
+        let offset = bytes[9] as usize;
+        if offset > IN_BYTES_PER_QUAD || offset as u64 > bytes_written {
+            return Err(PieceHasherError::InvalidCheckpoint(format!(
+                "Invalid buffer offset {}",
+                offset
+            )));
+        }
+
+        // All the bytes that are not buffered must have been hashed as
+        // complete quads.
+        let hashed = bytes_written - offset as u64;
+        if !hashed.is_multiple_of(IN_BYTES_PER_QUAD as u64) {
+            return Err(PieceHasherError::InvalidCheckpoint(format!(
+                "Buffer offset {} does not match {} bytes written",
+                offset, bytes_written
+            )));
+        }
+
+        let mut cursor = CHECKPOINT_HEADER_SIZE;
+        let mut buffer = [0u8; IN_BYTES_PER_QUAD];
+        buffer[..offset].copy_from_slice(read_bytes(bytes, &mut cursor, offset)?);
+
+        // Every quad produces two leaves and after pruning each layer holds a
+        // node only if the corresponding bit of the leaf count is set.
+        let leaves = hashed / IN_BYTES_PER_QUAD as u64 * 2;
+        let height = std::cmp::max(1, (u64::BITS - leaves.leading_zeros()) as usize);
+        let layer_count = read_bytes(bytes, &mut cursor, 1)?[0] as usize;
+        if layer_count != height {
+            return Err(PieceHasherError::InvalidCheckpoint(format!(
+                "Expected {} layers for {} bytes written, found {}",
+                height, bytes_written, layer_count
+            )));
+        }
+
+        let mut frontier = [None; MAX_HEIGHT as usize];
+        for (level, layer) in frontier[..layer_count].iter_mut().enumerate() {
+            let count = read_bytes(bytes, &mut cursor, 1)?[0] as usize;
+            if count as u64 != (leaves >> level) & 1 {
+                return Err(PieceHasherError::InvalidCheckpoint(format!(
+                    "Unexpected number of nodes {} in layer {}",
+                    count, level
+                )));
+            }
+
+            if count > 0 {
+                let node = read_bytes(bytes, &mut cursor, NODE_SIZE)?;
+                *layer = Some(MerkleTreeNode(node.try_into().unwrap()));
+            }
+        }
+
+        if cursor != bytes.len() {
+            return Err(invalid_checkpoint("Checkpoint has trailing bytes"));
+        }
+
+        Ok(PieceHasher {
+            bytes_written,
+            buffer,
+            offset,
+            frontier,
+            height: layer_count,
+            retained: None,
+            collector: None,
+            observer: None,
+            overflowed: false,
+            digest: [0; MAX_MULTIHASH_SIZE],
+        })
+    }
+
+    /// Combines the hasher for the left-hand part of the payload with the
+    /// hasher for the bytes that follow it, producing the same state as if
+    /// all bytes were written into a single hasher. Left part must be a
+    /// complete power of two subtree (127·2^k bytes) e.g. a hasher created
+    /// from a `Piece`, and no subtree of the right part may be larger than it.
+    pub fn merge(left: &PieceHasher, right: &PieceHasher) -> Result<Self, PieceHasherError> {
+        if left.overflowed || right.overflowed {
+            return Err(PieceHasherError::PayloadTooLarge);
+        }
+        let mut hasher = PieceHasher {
+            bytes_written: left.bytes_written,
+            buffer: left.buffer,
+            offset: left.offset,
+            frontier: left.frontier,
+            height: left.height,
+            retained: None,
+            collector: None,
+            observer: None,
+            overflowed: false,
+            digest: [0; MAX_MULTIHASH_SIZE],
+        };
+        // Last quad stays buffered until more bytes are written, so we hash it
+        // here if it is complete.
+        if hasher.offset == IN_BYTES_PER_QUAD {
+            let quad = hasher.buffer;
+            hasher.push_quad(&quad);
+            hasher.offset = 0;
+        }
+
+        let quads = hasher.quads();
+        if hasher.offset > 0 || !quads.is_power_of_two() {
+            return Err(PieceHasherError::InvalidMerge(format!(
+                "Left part of {} bytes is not a complete power of two subtree",
+                left.bytes_written
+            )));
+        }
+
+        // Layer of the frontier holding the left subtree root.
+        let level = quads.trailing_zeros() as usize + 1;
+        if right.frontier[level + 1..]
+            .iter()
+            .any(|node| node.is_some())
+        {
+            return Err(PieceHasherError::InvalidMerge(format!(
+                "Right part of {} bytes has subtrees larger than the left part of {} bytes",
+                right.bytes_written, left.bytes_written
+            )));
+        }
+
+        let bytes_written = left.bytes_written + right.bytes_written;
+        if bytes_written > MAX_PAYLOAD_SIZE {
+            return Err(PieceHasherError::PayloadTooLarge);
+        }
+        hasher.bytes_written = bytes_written;
+        hasher.buffer = right.buffer;
+        hasher.offset = right.offset;
+
+        // Right subtrees are added from the largest to the smallest so that
+        // layers below are empty when each one is added.
+        for (level, node) in right.frontier[..right.height].iter().enumerate().rev() {
+            if let Some(node) = node {
+                hasher.push_at(*node, level);
+            }
+        }
+
+        Ok(hasher)
+    }
+
+    // Number of complete quads that were hashed.
+    fn quads(&self) -> u64 {
+        (self.bytes_written - self.offset as u64) / IN_BYTES_PER_QUAD as u64
+    }
+
+    /// Hashes a complete quad and adds resulting pair of nodes to the
+    /// frontier.
+    fn push_quad(&mut self, quad: &QuadBuffer) {
+        let leaves = expand_quad(quad);
+        if self.observing_leaves() {
+            // Every node in the bottom frontier layer covers two leaves.
+            let first = self.width() * 2;
+            for (offset, leaf) in leaves.chunks_exact(NODE_SIZE).enumerate() {
+                let leaf = MerkleTreeNode(leaf.try_into().unwrap());
+                self.observe(0, first + offset as u64, &leaf);
+            }
+        }
+
+        let [left, right] = hash_leaves(&leaves);
+        self.push(left);
+        self.push(right);
+    }
+
+    /// Hashes a batch of complete quads (see `hash_batch`) and adds the root
+    /// of their subtree to the frontier.
+    fn push_batch(&mut self, bytes: &[u8]) {
+        let quads = (bytes.len() / IN_BYTES_PER_QUAD) as u64;
+        let root = if self.observing() {
+            let leaves = self.observing_leaves();
+            // Every node in the bottom frontier layer covers two leaves.
+            let first = self.width() * 2;
+            hash_batch(bytes, |level, offset, node| {
+                if level > 0 || leaves {
+                    self.observe(level, (first >> level) + offset, node);
+                }
+            })
+        } else {
+            hash_batch(bytes, |_, _, _| {})
+        };
+        // Each quad produces a node in the second layer.
+        self.push_at(root, quads.trailing_zeros() as usize + 1);
+    }
+
+    /// Adds a node to the bottom layer of the frontier combining it with the
+    /// pending nodes all the way up.
+    fn push(&mut self, node: MerkleTreeNode) {
+        self.push_at(node, 0);
+    }
+
+    /// Adds a subtree root to the given frontier layer. All the layers below
+    /// must be empty, which is the case when the subtree is aligned.
+    fn push_at(&mut self, node: MerkleTreeNode, level: usize) {
+        let mut node = node;
+        let mut level = level;
+        let observing = self.observing();
+        let mut index = 0;
+        if observing {
+            index = self.width() >> level;
+            self.observe(level + 1, index, &node);
+        }
+
+        while let Some(left) = self.frontier[level].take() {
+            node = compute_node(&left, &node);
+            level += 1;
+            if observing {
+                index >>= 1;
+                self.observe(level + 1, index, &node);
+            }
+        }
+
+        self.frontier[level] = Some(node);
+        self.height = max(self.height, level + 1);
+    }
+
+    /// Number of nodes added to the bottom frontier layer so far, including
+    /// ones that were already combined into the layers above.
+    fn width(&self) -> u64 {
+        self.frontier[..self.height]
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.is_some())
+            .map(|(level, _)| 1 << level)
+            .sum()
+    }
+
+    /// Whether computed nodes need to be reported to `observe`.
+    fn observing(&self) -> bool {
+        self.retained.is_some() || self.collector.is_some() || self.observer.is_some()
+    }
+
+    /// Whether leaves need to be reported to `observe`.
+    fn observing_leaves(&self) -> bool {
+        self.retained.is_some()
+            || self.observer.is_some()
+            || self
+                .collector
+                .as_ref()
+                .is_some_and(|collector| collector.leaves)
+    }
+
+    /// Reports node at the given tree level (where leaves are at level 0) and
+    /// index within that level. Nodes of each level are reported in order.
+    fn observe(&mut self, level: usize, index: u64, node: &MerkleTreeNode) {
+        if let Some(layers) = &mut self.retained {
+            if layers.len() <= level {
+                layers.resize_with(level + 1, Vec::new);
+            }
+            layers[level].push(*node);
+        }
+        if let Some(collector) = &mut self.collector {
+            collector.observe(level, index, node);
+        }
+        if let Some(observer) = &mut self.observer {
+            observer.observe(level, index, node);
+        }
+    }
+
+    /**
+     * Computes the tree root (and height) from the frontier without mutating
+     * it. Any partially filled quad is zero padded and layers with a single
+     * node are combined with the zero padded node of the corresponding level.
+     */
+    fn root(&self) -> (MerkleTreeNode, usize) {
+        self.root_with(|_, _, _| {})
+    }
+
+    /// Same as `root` but also passes every node computed on the way along
+    /// with its tree level and index to the `visit` function.
+    pub(crate) fn root_with<F: FnMut(usize, u64, &MerkleTreeNode)>(
+        &self,
+        mut visit: F,
+    ) -> (MerkleTreeNode, usize) {
+        let mut height = self.height;
+        let mut level = 0;
+        let mut carry = None;
+        // Index of the last node in the bottom frontier layer that covers the
+        // payload, nodes computed here are its ancestors.
+        let mut last = self.width().saturating_sub(1);
+
+        if self.offset > 0 || self.bytes_written == 0 {
+            let mut quad = self.buffer;
+            quad[self.offset..].fill(0);
+            let leaves = expand_quad(&quad);
+            let first = self.width() * 2;
+            for (offset, leaf) in leaves.chunks_exact(NODE_SIZE).enumerate() {
+                let leaf = MerkleTreeNode(leaf.try_into().unwrap());
+                visit(0, first + offset as u64, &leaf);
+            }
+
+            // Leaves are always added in pairs which is why the bottom layer
+            // is empty and the pair ends up in the layer above.
+            let [left, right] = hash_leaves(&leaves);
+            last = self.width() + 1;
+            visit(1, last - 1, &left);
+            visit(1, last, &right);
+            let node = compute_node(&left, &right);
+            visit(2, last >> 1, &node);
+            carry = Some(node);
+            level = 1;
+            height = max(height, 2);
+        }
+
+        loop {
+            match (self.frontier[level], carry) {
+                (Some(left), Some(right)) => {
+                    let node = compute_node(&left, &right);
+                    visit(level + 2, last >> (level + 1), &node);
+                    carry = Some(node);
+                    height = max(height, level + 2);
+                }
+                (Some(node), None) | (None, Some(node)) => {
+                    // Reached the top layer, so this is our root.
+                    if level + 1 >= height {
+                        return (node, height);
+                    }
+                    let zero_pad = zero_comm::from_level(level + 1).unwrap();
+                    let node = compute_node(&node, &zero_pad);
+                    visit(level + 2, last >> (level + 1), &node);
+                    carry = Some(node);
+                }
+                (None, None) => {}
+            }
+            level += 1;
+        }
     }
 }
 
@@ -125,18 +720,18 @@ impl Default for PieceHasher {
 
 // Implement conversion from Piece reference to PieceHasher that
 // contains that piece. Next updates will end up in the sibling of
-// the piece.
-impl From<&Piece> for PieceHasher {
-    fn from(piece: &Piece) -> Self {
+// the piece. Pieces the hasher could not produce are rejected.
+impl TryFrom<&Piece> for PieceHasher {
+    type Error = PieceHasherError;
+    fn try_from(piece: &Piece) -> Result<Self, Self::Error> {
+        piece.validate()?;
         let mut hasher = PieceHasher::default();
         // All but the last layer will be empty as they will be
         // collapsed into the the root node.
         let top = piece.height() - 1;
-        for _ in 0..top {
-            hasher.layers.push(vec![]);
-        }
         // Finally top layer will have only our piece root
-        hasher.layers[top] = vec![piece.root()];
+        hasher.frontier[top] = Some(piece.root());
+        hasher.height = piece.height();
 
         // Bytes written will correspond to the sum of original payload size
         // and applied 0-padding. Note that we have to account for padding
@@ -144,7 +739,7 @@ impl From<&Piece> for PieceHasher {
         // are not able to continue hashing from with-in the piece boundaries.
         hasher.bytes_written = piece.payload_size() + piece.padding_size();
 
-        hasher
+        Ok(hasher)
     }
 }
 
@@ -169,49 +764,194 @@ impl From<&[u8]> for PieceHasher {
 
 // Implement Hasher trait defined by the multihash crate for the PieceHasher
 // so that it could be use by multihash codec table.
+// Note that `Hasher::update` can not fail, use `PieceHasher::try_update` in
+// order to handle payloads exceeding `MAX_PAYLOAD_SIZE`.
 impl Hasher for PieceHasher {
+    /// Same as `PieceHasher::try_update`, for the callers that can not handle
+    /// errors. Writing bytes past `MAX_PAYLOAD_SIZE` is recorded instead, so
+    /// that every following `PieceHasher::try_update` fails with
+    /// `PieceHasherError::PayloadTooLarge` and `Hasher::finalize` returns an
+    /// empty digest until the hasher is reset.
     fn update(&mut self, bytes: &[u8]) {
-        self.try_update(bytes).unwrap();
+        if self.try_update(bytes).is_err() {
+            self.overflowed = true;
+        }
     }
     fn finalize(&mut self) -> &[u8] {
-        let mut layers = self.layers.clone();
-        let leaves = &mut layers[0];
+        if self.overflowed {
+            return &[];
+        }
+        let size = self.finish().write_digest(&mut self.digest);
 
-        if self.offset > 0 || self.bytes_written == 0 {
-            self.buffer[self.offset..].fill(0);
-            read_quad(&self.buffer, leaves);
+        &self.digest[..size]
+    }
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.bytes_written = 0;
+        self.overflowed = false;
+        self.frontier = [None; MAX_HEIGHT as usize];
+        self.height = 1;
+        if let Some(layers) = &mut self.retained {
+            layers.clear();
+            layers.push(Vec::new());
+        }
+        if let Some(collector) = &mut self.collector {
+            collector.clear();
         }
+    }
+}
+
+// Allows `io::copy` into the hasher. Writes exceeding `MAX_PAYLOAD_SIZE`
+// fail with an `io::Error`.
+impl io::Write for PieceHasher {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.try_update(bytes)?;
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Implement RustCrypto traits so that hasher can be used by generic code.
+#[cfg(feature = "digest")]
+impl digest::Update for PieceHasher {
+    /// Same as `Hasher::update`, writing bytes past `MAX_PAYLOAD_SIZE` fails
+    /// the following `PieceHasher::try_update` calls.
+    fn update(&mut self, bytes: &[u8]) {
+        Hasher::update(self, bytes);
+    }
+}
 
-        build(&mut layers);
+#[cfg(feature = "digest")]
+impl digest::Reset for PieceHasher {
+    fn reset(&mut self) {
+        Hasher::reset(self);
+    }
+}
 
-        let height = layers.len();
-        let root = layers[height - 1][0];
+fn invalid_checkpoint(reason: &str) -> PieceHasherError {
+    PieceHasherError::InvalidCheckpoint(String::from(reason))
+}
 
-        // encode padding
-        let mut padding_bytes = unsigned_varint::encode::u64_buffer();
-        let padding = unsigned_varint::encode::u64(
-            required_zero_padding(self.bytes_written),
-            &mut padding_bytes,
-        );
-        self.digest[0..padding.len()].copy_from_slice(&padding);
+// Reads `length` bytes from the checkpoint at the cursor and advances it.
+fn read_bytes<'a>(
+    bytes: &'a [u8],
+    cursor: &mut usize,
+    length: usize,
+) -> Result<&'a [u8], PieceHasherError> {
+    if *cursor + length > bytes.len() {
+        return Err(invalid_checkpoint("Checkpoint is truncated"));
+    }
+    let slice = &bytes[*cursor..*cursor + length];
+    *cursor += length;
+    Ok(slice)
+}
 
-        // set the tree height
-        self.digest[padding.len()] = height as u8;
+// Expands the quad with fr32 padding and hashes it into a pair of nodes.
+pub(crate) fn read_quad(source: &QuadBuffer) -> [MerkleTreeNode; 2] {
+    hash_leaves(&expand_quad(source))
+}
+
+// Hashes four leaves of the expanded quad into a pair of nodes.
+fn hash_leaves(buffer: &[u8; OUT_BYTES_PER_QUAD]) -> [MerkleTreeNode; 2] {
+    let mut blocks = [[0u8; BLOCK_SIZE]; 2];
+    blocks[0].copy_from_slice(&buffer[..BLOCK_SIZE]);
+    blocks[1].copy_from_slice(&buffer[BLOCK_SIZE..]);
+    let mut digests = [[0u8; NODE_SIZE]; 2];
+    hash_blocks(&blocks, &mut digests);
 
-        // copy the root hash
-        self.digest[padding.len() + 1..][..NODE_SIZE].copy_from_slice(&root.0);
+    [
+        MerkleTreeNode::from(digests[0]),
+        MerkleTreeNode::from(digests[1]),
+    ]
+}
 
-        return &self.digest[..padding.len() + 1 + NODE_SIZE];
+/// Returns the number of quads in the largest batch that can be hashed given
+/// that `quads` were already hashed and `available` more can be read. Batch
+/// must be a power of two wide and aligned with the quads already hashed so
+/// that its root fits into the hasher frontier.
+fn batch_width(quads: u64, available: u64) -> Option<u64> {
+    let available = available.min(BATCH_QUADS as u64);
+    if available < MIN_BATCH_QUADS {
+        return None;
     }
-    fn reset(&mut self) {
-        self.offset = 0;
-        self.bytes_written = 0;
-        self.layers.clear();
-        self.layers.push(Vec::new());
+
+    let mut width = 1u64 << (u64::BITS - 1 - available.leading_zeros());
+    if quads > 0 {
+        width = width.min(1u64 << quads.trailing_zeros());
+    }
+
+    if width >= MIN_BATCH_QUADS {
+        Some(width)
+    } else {
+        None
+    }
+}
+
+/// Computes the root of the subtree for the given bytes, which must contain a
+/// power of two number of quads up to `BATCH_QUADS`. Leaves of all the quads
+/// and then every layer of the subtree are hashed through the multi-lane
+/// kernel. Every node below the root is passed to `visit` along with its
+/// tree level and offset within that level of the subtree.
+pub(crate) fn hash_batch<F: FnMut(usize, u64, &MerkleTreeNode)>(
+    bytes: &[u8],
+    mut visit: F,
+) -> MerkleTreeNode {
+    let quads = bytes.len() / IN_BYTES_PER_QUAD;
+    debug_assert!(quads.is_power_of_two() && quads <= BATCH_QUADS);
+
+    let mut nodes = [MerkleTreeNode::empty(); 2 * BATCH_QUADS];
+    let mut parents = [MerkleTreeNode::empty(); BATCH_QUADS];
+
+    // Every quad expands into a pair of blocks, so `LANES / 2` quads are
+    // hashed together into the nodes of the first level.
+    let mut blocks = [[0u8; BLOCK_SIZE]; LANES];
+    let mut digests = [[0u8; NODE_SIZE]; LANES];
+    let group = LANES / 2;
+    for (index, chunk) in bytes.chunks(group * IN_BYTES_PER_QUAD).enumerate() {
+        let count = chunk.len() / IN_BYTES_PER_QUAD * 2;
+        for (offset, (pair, quad)) in blocks
+            .chunks_exact_mut(2)
+            .zip(chunk.chunks_exact(IN_BYTES_PER_QUAD))
+            .enumerate()
+        {
+            let leaves = expand_quad(quad.try_into().unwrap());
+            let first = ((index * group + offset) * 4) as u64;
+            for (offset, leaf) in leaves.chunks_exact(NODE_SIZE).enumerate() {
+                visit(
+                    0,
+                    first + offset as u64,
+                    &MerkleTreeNode(leaf.try_into().unwrap()),
+                );
+            }
+            pair[0].copy_from_slice(&leaves[..BLOCK_SIZE]);
+            pair[1].copy_from_slice(&leaves[BLOCK_SIZE..]);
+        }
+
+        hash_blocks(&blocks[..count], &mut digests[..count]);
+        for (node, digest) in nodes[index * LANES..].iter_mut().zip(&digests[..count]) {
+            *node = MerkleTreeNode(*digest);
+        }
+    }
+
+    let mut width = quads * 2;
+    let mut level = 1;
+    while width > 1 {
+        for (offset, node) in nodes[..width].iter().enumerate() {
+            visit(level, offset as u64, node);
+        }
+        compute_layer(&nodes[..width], &mut parents[..width / 2]);
+        width /= 2;
+        nodes[..width].copy_from_slice(&parents[..width]);
+        level += 1;
     }
+
+    nodes[0]
 }
 
-fn read_quad(source: &QuadBuffer, output: &mut Layer) {
+// Expands the quad with fr32 padding into four leaves.
+pub(crate) fn expand_quad(source: &QuadBuffer) -> [u8; OUT_BYTES_PER_QUAD] {
     let mut buffer = [0u8; OUT_BYTES_PER_QUAD];
     let mut offset = 0;
 
@@ -241,77 +981,29 @@ fn read_quad(source: &QuadBuffer, output: &mut Layer) {
     // we shim last 2-bits by shifting the last byte by two bits
     buffer[offset + 31] = source[IN_BYTES_PER_QUAD - 1] >> 2;
 
-    output.push(MerkleTreeNode::from(truncated_hash(
-        &buffer[0..NODE_SIZE * 2],
-    )));
-    output.push(MerkleTreeNode::from(truncated_hash(
-        &buffer[NODE_SIZE * 2..],
-    )));
-}
-
-/**
- * Prunes layers by combining node pairs into nodes in the next layer and
- * removing them from the layer that they were in. After pruning each layer
- * will end up with at most one node. New layers may be created in the process
- * when nodes from the top layer are combined.
- */
-fn prune(layers: &mut Layers) {
-    flush(layers, false);
+    buffer
 }
 
-/**
- * Flushes all the nodes in layers by combining node pairs into nodes in the
- * next layer. Layers with only one node are combined with zero padded nodes
- * (corresponding to the level of the layer). Unlike {@link prune} combined
- * nodes are not removed and layers are copied instead of been mutated.
- */
-fn build(layers: &mut Layers) {
-    flush(layers, true);
-}
-
-// Function to flush layers by combining nodes and optionally adding zero padding nodes
-fn flush(layers: &mut Layers, build: bool) {
-    // Note it is important that we do not mutate any of the layers otherwise
-    // writing more data into the hasher and computing the digest will produce
-    // wrong results.
-    let mut level = 0;
-    // We will walk up the tree until we reach the top layer. However, we may end
-    // up with creating new layers in the process, so we will keep track of the
-    while level < layers.len() {
-        let mut index = 0; // Initialize the index to 0
-
-        {
-            let height = layers.len();
-            let layer = &mut layers[level];
-
-            // If we have the odd number of nodes and we have not reached the top
-            // layer, we push a zero padding node corresponding to the current level.
-            if build && layer.len() % 2 > 0 && level + 1 < height {
-                let zero_pad = zero_comm::from_level(level + 1).unwrap(); // Create a zero padding node
-                layer.push(zero_pad); // Push the zero padding node to the current layer
-            }
-        }
-
-        while index + 1 < layers[level].len() {
-            let node = compute_node(&layers[level][index], &layers[level][index + 1]); // Compute a new node
-
-            if level + 1 < layers.len() {
-                layers[level + 1].push(node); // Otherwise, push the new node to the next layer
-            } else {
-                layers.push(vec![node]); // Push the new node to the current layer if it's the last layer
-            }
-
-            index += 2; // Increment the index
-        }
-
-        layers[level].splice(0..index, []);
-        level += 1; // Increment the level
+// Reverses `expand_quad` by dropping two padding bits of every leaf.
+pub(crate) fn unpad_quad(leaves: &[u8; OUT_BYTES_PER_QUAD]) -> QuadBuffer {
+    let mut quad = [0u8; IN_BYTES_PER_QUAD];
+    for bit in 0..IN_BYTES_PER_QUAD * 8 {
+        let source = bit / IN_BITS_FR * OUT_BITS_FR + bit % IN_BITS_FR;
+        let value = (leaves[source / 8] >> (source % 8)) & 1;
+        quad[bit / 8] |= value << (bit % 8);
     }
+    quad
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{expand_quad, unpad_quad, PieceHasher};
+    use crate::error::PieceHasherError;
+    use crate::piece::Piece;
+    use crate::piece_tree::TreeHasher;
+    use multihash_derive::Hasher;
     use std::convert::{TryFrom, TryInto};
+    use std::sync::{Arc, Mutex};
 
     pub struct Varint([u8; 10]);
 
@@ -344,4 +1036,369 @@ mod tests {
 
         assert_eq!(varint.try_into(), Ok(2u64.pow(63)));
     }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let payload: Vec<u8> = (0..127 * 9 + 50).map(|n| n as u8).collect();
+        let expected = PieceHasher::from(&payload[..]).multihash();
+
+        for split in [0, 1, 126, 127, 128, 127 * 4, 127 * 5 + 3, payload.len()] {
+            let mut hasher = PieceHasher::new();
+            hasher.update(&payload[..split]);
+
+            let mut restored = PieceHasher::restore(&hasher.checkpoint()).unwrap();
+            assert_eq!(restored.bytes_written, split as u64);
+            restored.update(&payload[split..]);
+
+            assert_eq!(restored.multihash(), expected);
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_from_piece() {
+        let hasher = PieceHasher::try_from(&Piece::new(10)).unwrap();
+        let restored = PieceHasher::restore(&hasher.checkpoint()).unwrap();
+
+        assert_eq!(restored.multihash(), hasher.multihash());
+    }
+
+    #[test]
+    fn test_checkpoint_rejects_invalid() {
+        let mut hasher = PieceHasher::new();
+        hasher.update(&[1u8; 127 * 3 + 5]);
+        let checkpoint = hasher.checkpoint();
+
+        // truncated
+        assert!(PieceHasher::restore(&checkpoint[..checkpoint.len() - 1]).is_err());
+        assert!(PieceHasher::restore(&[]).is_err());
+
+        // trailing bytes
+        let mut trailing = checkpoint.clone();
+        trailing.push(0);
+        assert!(PieceHasher::restore(&trailing).is_err());
+
+        // unknown version
+        let mut version = checkpoint.clone();
+        version[0] = 2;
+        assert!(PieceHasher::restore(&version).is_err());
+
+        // bytes written that do not match the layers
+        let mut written = checkpoint.clone();
+        written[1..9].copy_from_slice(&(127u64 * 4 + 5).to_le_bytes());
+        assert!(PieceHasher::restore(&written).is_err());
+
+        // bytes written that do not match the buffer offset
+        let mut offset = checkpoint.clone();
+        offset[1..9].copy_from_slice(&(127u64 * 3 + 6).to_le_bytes());
+        assert!(PieceHasher::restore(&offset).is_err());
+
+        assert!(PieceHasher::restore(&checkpoint).is_ok());
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        let mut hasher = PieceHasher::try_from(&Piece::new(50)).unwrap();
+
+        assert_eq!(
+            hasher.try_update(&[0]),
+            Err(PieceHasherError::PayloadTooLarge)
+        );
+        // Failed `try_update` leaves the hasher usable.
+        assert_eq!(hasher.try_update(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_update_too_large() {
+        let mut hasher = PieceHasher::try_from(&Piece::new(50)).unwrap();
+        let piece = hasher.piece();
+
+        // Error is recorded instead of panicking and reported afterwards.
+        Hasher::update(&mut hasher, &[0]);
+        assert_eq!(
+            hasher.try_update(&[]),
+            Err(PieceHasherError::PayloadTooLarge)
+        );
+        assert_eq!(Hasher::finalize(&mut hasher), &[] as &[u8]);
+        assert_eq!(hasher.piece(), piece);
+        assert_eq!(
+            PieceHasher::merge(&PieceHasher::new(), &hasher).err(),
+            Some(PieceHasherError::PayloadTooLarge)
+        );
+
+        Hasher::reset(&mut hasher);
+        assert_eq!(hasher.try_update(&[1]), Ok(()));
+        assert!(!Hasher::finalize(&mut hasher).is_empty());
+    }
+
+    #[test]
+    fn test_digest_into_buffer_too_small() {
+        let hasher = PieceHasher::from(&[0u8; 65]);
+        let mut target = [0u8; 40];
+
+        assert_eq!(
+            hasher.digest_into(&mut target, 4, true),
+            Err(PieceHasherError::BufferTooSmall {
+                required: 37,
+                available: 36
+            })
+        );
+        assert_eq!(hasher.digest_into(&mut target, 4, false), Ok(34));
+    }
+
+    #[test]
+    fn test_intermediate_digests() {
+        let payload: Vec<u8> = (0..127 * 17 + 3).map(|n| (n * 7) as u8).collect();
+        let mut hasher = PieceHasher::new();
+
+        for chunk in payload.chunks(100) {
+            hasher.update(chunk);
+            let written = hasher.bytes_written as usize;
+
+            assert_eq!(
+                hasher.multihash(),
+                PieceHasher::from(&payload[..written]).multihash()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let payload: Vec<u8> = (0..127 * 40 + 9).map(|n| (n % 13) as u8).collect();
+        let expected = PieceHasher::from(&payload[..]);
+
+        // 16 + 16 + 8 + rest
+        let first = PieceHasher::from(&payload[..127 * 16]);
+        let second = PieceHasher::from(&payload[127 * 16..127 * 32]);
+        let third = PieceHasher::from(&payload[127 * 32..127 * 40]);
+        let rest = PieceHasher::from(&payload[127 * 40..]);
+
+        let left = PieceHasher::merge(&first, &second).unwrap();
+        let right = PieceHasher::merge(&third, &rest).unwrap();
+        let mut merged = PieceHasher::merge(&left, &right).unwrap();
+
+        assert_eq!(merged.multihash(), expected.multihash());
+        assert_eq!(merged.checkpoint(), expected.checkpoint());
+
+        // Hashing can continue after the merge
+        merged.update(&[7u8; 300]);
+        let mut expected = expected;
+        expected.update(&[7u8; 300]);
+        assert_eq!(merged.multihash(), expected.multihash());
+    }
+
+    #[test]
+    fn test_merge_piece() {
+        let piece = Piece::new(5);
+        let left = PieceHasher::try_from(&piece).unwrap();
+        let right = PieceHasher::from(&[1u8; 100]);
+
+        let mut expected = PieceHasher::try_from(&piece).unwrap();
+        expected.update(&[1u8; 100]);
+
+        assert_eq!(
+            PieceHasher::merge(&left, &right).unwrap().multihash(),
+            expected.multihash()
+        );
+    }
+
+    #[test]
+    fn test_merge_invalid() {
+        let partial = PieceHasher::from(&[0u8; 127 * 2 + 1]);
+        let uneven = PieceHasher::from(&[0u8; 127 * 3]);
+        let small = PieceHasher::from(&[0u8; 127 * 2]);
+        let large = PieceHasher::from(&[0u8; 127 * 4 + 1]);
+
+        assert!(PieceHasher::merge(&partial, &small).is_err());
+        assert!(PieceHasher::merge(&uneven, &small).is_err());
+        assert!(PieceHasher::merge(&PieceHasher::new(), &small).is_err());
+        assert!(PieceHasher::merge(&small, &large).is_err());
+        assert!(PieceHasher::merge(&small, &uneven).is_ok());
+    }
+
+    #[test]
+    fn test_unpad_quad() {
+        let quad: Vec<u8> = (0..127).map(|n| (n * 37 + 11) as u8).collect();
+        let quad: [u8; 127] = quad.try_into().unwrap();
+        assert_eq!(unpad_quad(&expand_quad(&quad)), quad);
+        assert_eq!(unpad_quad(&expand_quad(&[0xff; 127])), [0xff; 127]);
+    }
+
+    #[test]
+    fn test_collect_proofs() {
+        for size in [0, 100, 127, 127 * 3 + 1, 127 * 8, 127 * 2048 + 5] {
+            let payload: Vec<u8> = (0..size).map(|n| (n * 3) as u8).collect();
+            let mut tree_hasher = TreeHasher::new();
+            tree_hasher.try_update(&payload).unwrap();
+            let (piece, tree) = tree_hasher.finish();
+
+            // First and last leaves, nodes in the zero padding and around the
+            // payload boundary.
+            let leaves = tree.leaves().len() as u64;
+            let boundary = (size as u64).div_ceil(127) * 4;
+            let mut targets = vec![(0, 0), (0, leaves - 1), (1, leaves / 2 - 1), (0, 1)];
+            targets.push((0, boundary.min(leaves - 1)));
+            targets.push((0, boundary.saturating_sub(1)));
+            targets.push((2, boundary / 4));
+            targets.push((tree.height(), 0));
+            targets.retain(|(level, index)| tree.node(*level, *index).is_some());
+
+            let mut hasher = PieceHasher::with_proofs(&targets);
+            for chunk in payload.chunks(1000) {
+                hasher.try_update(chunk).unwrap();
+            }
+            let (result, proofs) = hasher.proofs().unwrap();
+            assert_eq!(result, piece);
+            assert_eq!(proofs.len(), targets.len());
+
+            for ((level, index), (node, proof)) in targets.iter().zip(proofs.iter()) {
+                assert_eq!(*node, tree.node(*level, *index).unwrap());
+                assert_eq!(*proof, tree.prove(*level, *index).unwrap());
+                proof.verify(&piece.root(), node, *level).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_collect_proofs_out_of_range() {
+        let mut hasher = PieceHasher::with_proofs(&[(0, 3), (0, 4)]);
+        hasher.try_update(&[1u8; 100]).unwrap();
+        assert_eq!(
+            hasher.proofs(),
+            Err(PieceHasherError::NodeOutOfRange { level: 0, index: 4 })
+        );
+
+        // Tree grows as more bytes are written
+        hasher.try_update(&[1u8; 100]).unwrap();
+        assert_eq!(hasher.proofs().unwrap().1.len(), 2);
+
+        hasher.reset();
+        hasher.try_update(&[1u8; 100]).unwrap();
+        assert!(hasher.proofs().is_err());
+
+        let (piece, proofs) = PieceHasher::new().proofs().unwrap();
+        assert_eq!(piece, PieceHasher::new().piece());
+        assert!(proofs.is_empty());
+    }
+
+    #[test]
+    fn test_observer() {
+        for size in [0usize, 1, 127, 128, 127 * 3 + 5, 127 * 16, 127 * 2048 + 3] {
+            let payload: Vec<u8> = (0..size).map(|i| (i * 3 + 1) as u8).collect();
+            let observed = Arc::new(Mutex::new(Vec::new()));
+            let nodes = observed.clone();
+            let mut hasher = PieceHasher::with_observer(move |level, index, node: &_| {
+                nodes.lock().unwrap().push((level, index, *node));
+            });
+            hasher.try_update(&payload).unwrap();
+            // Computing the root does not report anything.
+            let count = observed.lock().unwrap().len();
+            hasher.piece();
+            hasher.multihash();
+            assert_eq!(observed.lock().unwrap().len(), count);
+            let piece = hasher.finish();
+            assert_eq!(piece, PieceHasher::from(payload.as_slice()).piece());
+            // Nodes are not reported again once finished.
+            let count = observed.lock().unwrap().len();
+            assert_eq!(hasher.finish(), piece);
+            hasher.try_update(&[1]).unwrap();
+            hasher.finish();
+            assert_eq!(observed.lock().unwrap().len(), count);
+
+            let mut tree = TreeHasher::new();
+            tree.try_update(&payload).unwrap();
+            let (_, tree) = tree.finish();
+
+            let observed = observed.lock().unwrap();
+            for (level, index, node) in observed.iter() {
+                assert_eq!(tree.node(*level, *index), Some(*node));
+            }
+            // Every leaf covering the payload and every ancestor of it gets
+            // reported once, including the root.
+            let leaves = size.max(1).div_ceil(127) * 4;
+            for level in 0..=tree.height() {
+                let width = leaves.div_ceil(1 << level);
+                let count = observed.iter().filter(|(at, _, _)| *at == level).count();
+                assert_eq!(count, width, "level {} of {} bytes", level, size);
+            }
+            assert_eq!(observed.last(), Some(&(piece.height(), 0, piece.root())));
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_observed_hasher_is_thread_safe() {
+        fn assert_thread_safe<T: Send + Sync>(_: &T) {}
+        let hasher = PieceHasher::with_observer(|_, _, _: &_| {});
+        assert_thread_safe(&hasher);
+    }
+
+    #[test]
+    fn test_batches_match_quads() {
+        let payload: Vec<u8> = (0..127 * 1000 + 42).map(|i| (i * 7 + 3) as u8).collect();
+
+        // Writes of a single quad are never batched.
+        let mut quads = PieceHasher::new();
+        let mut tree = TreeHasher::new();
+        for quad in payload.chunks(127) {
+            quads.try_update(quad).unwrap();
+            tree.try_update(quad).unwrap();
+        }
+        let (piece, tree) = tree.finish();
+        assert_eq!(quads.piece(), piece);
+
+        // Unaligned writes are batched into subtrees of different widths.
+        for split in [0, 5, 127 * 3 + 11, 127 * 8, 127 * 300 + 1] {
+            let mut hasher = PieceHasher::new();
+            hasher.try_update(&payload[..split]).unwrap();
+            hasher.try_update(&payload[split..]).unwrap();
+            assert_eq!(hasher.multihash(), quads.multihash(), "split {}", split);
+
+            let mut batched = TreeHasher::new();
+            batched.try_update(&payload[..split]).unwrap();
+            batched.try_update(&payload[split..]).unwrap();
+            assert_eq!(batched.finish(), (piece, tree.clone()), "split {}", split);
+        }
+    }
+
+    #[test]
+    fn test_io_write() {
+        let payload: Vec<u8> = (0..127 * 9 + 50).map(|n| n as u8).collect();
+        let expected = PieceHasher::from(&payload[..]);
+
+        let mut hasher = PieceHasher::new();
+        std::io::copy(&mut &payload[..], &mut hasher).unwrap();
+        assert_eq!(hasher.multihash(), expected.multihash());
+
+        let piece = PieceHasher::hash_reader(std::io::Cursor::new(&payload)).unwrap();
+        assert_eq!(piece, expected.piece());
+        assert_eq!(piece.multihash(), expected.multihash());
+        assert_eq!(piece.link(), expected.link());
+    }
+
+    #[test]
+    fn test_io_write_too_large() {
+        use std::io::Write;
+        let mut hasher = PieceHasher::try_from(&Piece::new(50)).unwrap();
+        let error = Write::write(&mut hasher, &[0]).unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::Other);
+        assert_eq!(error.to_string(), "Payload size exceeded");
+    }
+
+    #[cfg(feature = "digest")]
+    #[test]
+    fn test_digest_traits() {
+        fn digest<D: digest::Update + digest::Reset>(hasher: &mut D, bytes: &[u8]) {
+            hasher.reset();
+            hasher.update(bytes);
+        }
+
+        let mut hasher = PieceHasher::from(&[1u8; 300]);
+        digest(&mut hasher, &[2u8; 500]);
+
+        assert_eq!(
+            hasher.multihash(),
+            PieceHasher::from(&[2u8; 500]).multihash()
+        );
+    }
 }
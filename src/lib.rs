@@ -1,95 +1,46 @@
-use js_sys::Error;
-use wasm_bindgen::prelude::*;
+pub mod challenge;
 pub mod constant;
+mod error;
 mod hasher;
+#[cfg(feature = "parallel")]
+mod parallel;
 mod piece;
+mod piece_tree;
+pub mod proof;
+mod size;
+#[cfg(feature = "futures")]
+mod stream;
 pub mod tree;
+mod tree_store;
 mod util;
-mod zero_comm;
-use hasher::{PieceHasher, CODE_SIZE, HEIGHT_SIZE, ROOT_SIZE};
-use multihash_derive::Hasher;
-use util::{required_zero_padding, varint_estimate};
+#[cfg(feature = "wasm")]
+mod wasm;
+pub mod zero_comm;
+pub use error::PieceHasherError;
+pub use hasher::{MaybeSendSync, NodeObserver, PieceHasher, MAX_PAYLOAD_SIZE};
+pub use piece::{Piece, PieceBuilder};
+pub use piece_tree::{PieceTree, TreeHasher};
+pub use size::{PaddedPieceSize, PieceSizes, UnpaddedPieceSize};
+#[cfg(feature = "futures")]
+pub use stream::{hash_stream, AsyncWriter, StreamError};
+pub use tree_store::{TreeStore, TreeWriter};
 pub mod multihash;
 
-type PieceMultihasher = PieceHasher;
-
-#[wasm_bindgen(inspectable)]
-impl PieceHasher {
-    /// Creates a new hasher
-    #[wasm_bindgen(constructor)]
-    pub fn create() -> PieceMultihasher {
-        PieceHasher::default()
-    }
-
-    #[wasm_bindgen]
-    pub fn count(&self) -> u64 {
-        self.bytes_written
-    }
-    /// Resets the hasher state
-    #[wasm_bindgen]
-    pub fn reset(&mut self) {
-        Hasher::reset(self);
-    }
-
-    #[wasm_bindgen]
-    pub fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
-        PieceHasher::try_update(self, bytes)
-    }
-
-    #[wasm_bindgen(js_name = digestInto)]
-    pub fn read(
-        &mut self,
-        target: &mut [u8],
-        offset: Option<usize>,
-        use_prefix: Option<bool>,
-    ) -> usize {
-        let hash = self.multihash();
-        let byte_offset = offset.unwrap_or(0);
-        if use_prefix.unwrap_or(true) {
-            let bytes = hash.to_bytes();
-            target[byte_offset..byte_offset + bytes.len()].copy_from_slice(&bytes);
-            bytes.len()
-        } else {
-            let bytes = hash.digest();
-            target[byte_offset..byte_offset + bytes.len()].copy_from_slice(&bytes);
-            bytes.len()
-        }
-    }
-
-    #[wasm_bindgen(js_name = digestByteLength)]
-    pub fn digest_size(&self) -> usize {
-        let padding = required_zero_padding(self.bytes_written);
-
-        varint_estimate(padding) + HEIGHT_SIZE + ROOT_SIZE
-    }
-
-    #[wasm_bindgen(js_name = multihashByteLength)]
-    pub fn multihash_size(&self) -> usize {
-        let hash_size = self.digest_size();
-
-        CODE_SIZE + varint_estimate(hash_size as u64) + hash_size
-    }
-}
-
-#[wasm_bindgen]
-pub fn create() -> PieceMultihasher {
-    PieceHasher::default()
-}
-
 #[cfg(test)]
 mod tests {
-    use std::hash;
-
-    use crate::create;
+    use crate::piece::Piece;
+    use crate::PieceHasher;
+    use multihash_derive::MultihashDigest;
+    use std::convert::TryFrom;
 
     #[test]
     fn test_lib() {
-        let mut hasher = create();
+        let mut hasher = PieceHasher::new();
         let data = [0u8; 65];
-        hasher.write(&data);
+        hasher.try_update(&data).unwrap();
 
         let mut out = [0u8; 32 + 1 + 9];
-        let size = hasher.read(&mut out, None, None);
+        let size = hasher.digest_into(&mut out, 0, true).unwrap();
         assert_eq!(hasher.multihash_size(), size);
 
         assert_eq!(
@@ -102,9 +53,6 @@ mod tests {
         );
     }
 
-    use crate::piece::Piece;
-    use crate::PieceHasher;
-    use multihash_derive::MultihashDigest;
     #[test]
     fn test_multihash() {
         let hash = crate::multihash::Code::PieceHasher.digest(b"hello world");
@@ -115,7 +63,7 @@ mod tests {
 
     #[test]
     fn test_0_bytes() {
-        let mut hasher = PieceHasher::from(&[]);
+        let hasher = PieceHasher::from(&[]);
         let hash = hasher.multihash();
 
         assert_eq!(hash.code(), 0x1011);
@@ -138,7 +86,7 @@ mod tests {
 
     #[test]
     fn test_127_bytes() {
-        let mut hasher = PieceHasher::from(&[0; 127]);
+        let hasher = PieceHasher::from(&[0; 127]);
         assert_eq!(hasher.digest_size(), 34);
         let hash = hasher.multihash();
 
@@ -163,7 +111,7 @@ mod tests {
     #[test]
     fn test_128_bytes() {
         let payload = [0u8; 128];
-        let mut hasher = PieceHasher::from(&payload);
+        let hasher = PieceHasher::from(&payload);
         let hash = hasher.multihash();
 
         assert_eq!(hasher.digest_size(), 34);
@@ -187,7 +135,7 @@ mod tests {
     }
     #[test]
     fn test_32() {
-        let mut hasher = PieceHasher::from(&Piece::new(30));
+        let hasher = PieceHasher::try_from(&Piece::new(30)).unwrap();
 
         assert_eq!(hasher.digest_size(), 34);
 
@@ -213,7 +161,7 @@ mod tests {
 
     #[test]
     fn test_64() {
-        let mut hasher = PieceHasher::from(&Piece::new(31));
+        let hasher = PieceHasher::try_from(&Piece::new(31)).unwrap();
 
         assert_eq!(
             hasher.link().to_string(),
@@ -282,7 +230,7 @@ mod tests {
         // and the remaining 4 bytes are 0
         payload[127 * 4..].fill(0);
 
-        let mut hasher = PieceHasher::from(&payload);
+        let hasher = PieceHasher::from(&payload);
         assert_eq!(hasher.digest_size(), 35);
         let hash = hasher.multihash();
 
@@ -322,7 +270,7 @@ mod tests {
         let mut payload = data.to_vec();
         payload.push(0);
 
-        let mut hasher = PieceHasher::from(&payload[..]);
+        let hasher = PieceHasher::from(&payload[..]);
         assert_eq!(hasher.digest_size(), 35);
         let hash = hasher.multihash();
 
@@ -345,7 +293,7 @@ mod tests {
 
     #[test]
     fn test_js_case() {
-        let mut hasher = PieceHasher::from(&[0u8; 65]);
+        let hasher = PieceHasher::from(&[0u8; 65]);
         let hash = hasher.multihash();
 
         assert_eq!(hash.size(), 34);
@@ -0,0 +1,82 @@
+use crate::constant::IN_BYTES_PER_QUAD;
+use crate::hasher::{hash_batch, BATCH_QUADS};
+use crate::tree::{compute_node, MerkleTreeNode};
+
+/// Smallest number of quads (~127KiB of payload) worth hashing on the thread
+/// pool. Smaller writes go through the sequential path.
+const MIN_PARALLEL_QUADS: u64 = 1024;
+
+/// Subtrees of up to this many quads are hashed on the current thread as a
+/// single batch, which keeps rayon task overhead negligible.
+const SEQUENTIAL_QUADS: usize = BATCH_QUADS;
+
+/// Returns the number of quads in the largest subtree that can be hashed in
+/// parallel given that `quads` were already hashed and `available` more can
+/// be read. Subtree must be a power of two wide and aligned with the quads
+/// already hashed so that its root fits into the hasher frontier.
+pub fn subtree_width(quads: u64, available: u64) -> Option<u64> {
+    if available < MIN_PARALLEL_QUADS {
+        return None;
+    }
+
+    let mut width = 1u64 << (u64::BITS - 1 - available.leading_zeros());
+    if quads > 0 {
+        width = width.min(1u64 << quads.trailing_zeros());
+    }
+
+    if width >= MIN_PARALLEL_QUADS {
+        Some(width)
+    } else {
+        None
+    }
+}
+
+/// Computes the root of the subtree for the given bytes, which must contain a
+/// power of two number of quads. Halves are hashed on the rayon thread pool.
+pub fn hash_subtree(bytes: &[u8]) -> MerkleTreeNode {
+    let quads = bytes.len() / IN_BYTES_PER_QUAD;
+    if quads <= SEQUENTIAL_QUADS {
+        hash_batch(bytes, |_, _, _| {})
+    } else {
+        let (left, right) = bytes.split_at(bytes.len() / 2);
+        let (left, right) = rayon::join(|| hash_subtree(left), || hash_subtree(right));
+        compute_node(&left, &right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parallel::subtree_width;
+    use crate::PieceHasher;
+    use multihash_derive::Hasher;
+
+    #[test]
+    fn test_subtree_width() {
+        assert_eq!(subtree_width(0, 1023), None);
+        assert_eq!(subtree_width(0, 1024), Some(1024));
+        assert_eq!(subtree_width(0, 3000), Some(2048));
+        assert_eq!(subtree_width(1024, 4096), Some(1024));
+        assert_eq!(subtree_width(4096, 4096), Some(4096));
+        assert_eq!(subtree_width(4097, 4096), None);
+    }
+
+    #[test]
+    fn test_matches_sequential() {
+        let payload: Vec<u8> = (0..127 * 5000 + 42).map(|n| (n % 251) as u8).collect();
+
+        // Small writes never reach the parallel path.
+        let mut sequential = PieceHasher::new();
+        for chunk in payload.chunks(1000) {
+            sequential.update(chunk);
+        }
+
+        // Unaligned first write followed by a large one.
+        let mut parallel = PieceHasher::new();
+        parallel.update(&payload[..127 * 3 + 11]);
+        parallel.update(&payload[127 * 3 + 11..]);
+
+        assert_eq!(parallel.multihash(), sequential.multihash());
+        assert_eq!(parallel.checkpoint(), sequential.checkpoint());
+        assert_eq!(PieceHasher::from(&payload[..]).link(), sequential.link());
+    }
+}
@@ -1,9 +1,21 @@
-use crate::constant::{IN_BYTES_PER_QUAD, OUT_BYTES_PER_QUAD};
-use crate::tree::MerkleTreeNode;
+use crate::constant::NODE_SIZE;
+use crate::error::PieceHasherError;
+use crate::hasher::{
+    CODE, FIL_COMMITMENT_UNSEALED, HEIGHT_SIZE, MAX_HEIGHT, MAX_MULTIHASH_SIZE, RAW, ROOT_SIZE,
+    SHA2_256_TRUNC254_PADDED,
+};
+use crate::size::{unpadded_size, MIN_HEIGHT};
+use crate::tree::{compute_node, MerkleTreeNode};
 use crate::util::from_height;
 use crate::zero_comm::from_level;
+use multihash::Multihash;
+use std::convert::{TryFrom, TryInto};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
 
 // Filecoin piece representation
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Piece {
     root: MerkleTreeNode,
     height: usize,
@@ -14,6 +26,9 @@ impl Piece {
     /// Creates a piece for the given tree height filled with zeros.
     /// If you need to create a piece for a tree containing data other than
     /// zeros, use `with_root` instead.
+    ///
+    /// Panics if there is no zero commitment for the given height, use
+    /// `Piece::try_new` or `Piece::builder` to get errors instead.
     pub fn new(height: usize) -> Self {
         Piece {
             height,
@@ -22,6 +37,30 @@ impl Piece {
         }
     }
 
+    /// Same as `Piece::new` but fails if the hasher could not produce a tree
+    /// of the given height.
+    pub fn try_new(height: usize) -> Result<Self, PieceHasherError> {
+        Piece::builder(height).build()
+    }
+
+    /// Creates builder for the piece of the given height, which is checked
+    /// along with the padding once the piece is built.
+    pub fn builder(height: usize) -> PieceBuilder {
+        PieceBuilder {
+            height,
+            root: None,
+            size: Size::Padding(0),
+        }
+    }
+
+    pub(crate) fn from_parts(root: MerkleTreeNode, height: usize, padding_size: u64) -> Self {
+        Piece {
+            root,
+            height,
+            padding_size,
+        }
+    }
+
     /// Sets the root of the given piece. This is generally used when creating
     /// a known piece e.g. `Piece::new(29).with_root(root)`.
     pub fn with_root(&mut self, root: MerkleTreeNode) -> &mut Self {
@@ -29,7 +68,8 @@ impl Piece {
         self
     }
 
-    /// Can be used to set the padding used when creating a piece.
+    /// Can be used to set the padding used when creating a piece. Padding is
+    /// not checked against the piece capacity, use `Piece::builder` for that.
     pub fn with_padding_size(&mut self, padding_size: u64) -> &mut Self {
         self.padding_size = padding_size;
         self
@@ -59,7 +99,553 @@ impl Piece {
 
     /// Payload size in bytes.
     pub fn payload_size(&self) -> u64 {
-        let padded_size = self.size() / OUT_BYTES_PER_QUAD as u64 * IN_BYTES_PER_QUAD as u64;
-        padded_size - self.padding_size()
+        self.capacity() - self.padding_size()
+    }
+
+    /// Piece multihash with the `0x1011` code.
+    pub fn multihash(&self) -> Multihash {
+        let mut digest = [0u8; MAX_MULTIHASH_SIZE];
+        let size = self.write_digest(&mut digest);
+        Multihash::wrap(CODE, &digest[..size]).unwrap()
+    }
+
+    /// Piece CID v1 with a `raw` codec.
+    pub fn link(&self) -> cid::Cid {
+        cid::Cid::new_v1(RAW as u64, self.multihash())
+    }
+
+    /// Combines two pieces of the same height into the piece of their
+    /// parent. Padding of the left piece ends up in the middle of the
+    /// payload, so it is accounted as payload and only padding of the right
+    /// piece remains. Pieces the hasher could not produce are rejected.
+    pub fn join(left: &Piece, right: &Piece) -> Result<Piece, PieceHasherError> {
+        left.validate()?;
+        right.validate()?;
+        if left.height != right.height {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Can not join pieces of heights {} and {}",
+                left.height, right.height
+            )));
+        }
+        let height = left.height + 1;
+        if height > MAX_HEIGHT as usize {
+            return Err(PieceHasherError::LevelOutOfRange {
+                level: height,
+                max: MAX_HEIGHT as usize,
+            });
+        }
+
+        let root = compute_node(&left.root, &right.root);
+        Piece::checked(root, height, right.padding_size)
+    }
+
+    /// Extends the piece with zero padding up to the given height by
+    /// combining its root with the zero commitments of every level on the
+    /// way, which is the same as hashing the payload padded to that height.
+    pub fn pad_to_height(&self, height: usize) -> Result<Piece, PieceHasherError> {
+        self.validate()?;
+        if height < self.height {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Can not pad piece of height {} to height {}",
+                self.height, height
+            )));
+        }
+        if height > MAX_HEIGHT as usize {
+            return Err(PieceHasherError::LevelOutOfRange {
+                level: height,
+                max: MAX_HEIGHT as usize,
+            });
+        }
+
+        let mut root = self.root;
+        for level in self.height..height {
+            root = compute_node(&root, &from_level(level).unwrap());
+        }
+        let piece = Piece::from_parts(root, height, 0);
+        let padding_size = piece.payload_size() - self.payload_size();
+        Ok(Piece::from_parts(root, height, padding_size))
+    }
+
+    /// PieceCIDv1 (`fil-commitment-unsealed` codec with the
+    /// `sha2-256-trunc254-padded` multihash) of the piece along with its
+    /// padded size, which v1 links do not encode.
+    pub fn link_v1(&self) -> (cid::Cid, u64) {
+        let multihash = Multihash::wrap(SHA2_256_TRUNC254_PADDED, &self.root.0).unwrap();
+        let link = cid::Cid::new_v1(FIL_COMMITMENT_UNSEALED, multihash);
+        (link, self.size())
+    }
+
+    /// Creates a piece from the PieceCIDv1, padded piece size and the size
+    /// of the payload it holds. Size must be a power of two the hasher could
+    /// produce and the payload must fit in it.
+    pub fn from_v1(
+        link: &cid::Cid,
+        size: u64,
+        payload_size: u64,
+    ) -> Result<Self, PieceHasherError> {
+        let multihash = link.hash();
+        if link.version() != cid::Version::V1
+            || link.codec() != FIL_COMMITMENT_UNSEALED
+            || multihash.code() != SHA2_256_TRUNC254_PADDED
+            || multihash.digest().len() != ROOT_SIZE
+        {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Expected PieceCIDv1, got {}",
+                link
+            )));
+        }
+        if !size.is_power_of_two() || size < NODE_SIZE as u64 {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Piece size {} is not a power of two",
+                size
+            )));
+        }
+
+        let height = (size / NODE_SIZE as u64).trailing_zeros() as usize;
+        let root = MerkleTreeNode(multihash.digest().try_into().unwrap());
+        Piece::builder(height)
+            .root(root)
+            .payload_size(payload_size)
+            .build()
+    }
+
+    /// Creates a piece making sure the hasher could produce a tree of the
+    /// given height and that padding does not exceed its capacity.
+    fn checked(
+        root: MerkleTreeNode,
+        height: usize,
+        padding_size: u64,
+    ) -> Result<Self, PieceHasherError> {
+        let piece = Piece::from_parts(root, height, padding_size);
+        piece.validate()?;
+        Ok(piece)
+    }
+
+    /// Checks that the hasher could produce a tree of the piece height and
+    /// that padding does not exceed its capacity.
+    pub(crate) fn validate(&self) -> Result<(), PieceHasherError> {
+        if self.height < MIN_HEIGHT || self.height > MAX_HEIGHT as usize {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Height {} is outside of the {}..={} range",
+                self.height, MIN_HEIGHT, MAX_HEIGHT
+            )));
+        }
+        if self.padding_size > self.capacity() {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Padding of {} bytes exceeds the capacity of {} bytes",
+                self.padding_size,
+                self.capacity()
+            )));
+        }
+        Ok(())
+    }
+
+    // Number of payload bytes that fit in the piece before fr32 padding.
+    fn capacity(&self) -> u64 {
+        unpadded_size(self.size())
+    }
+
+    /// Writes the multihash digest (padding varint, height and root) into the
+    /// given buffer and returns number of bytes written.
+    pub(crate) fn write_digest(&self, digest: &mut [u8; MAX_MULTIHASH_SIZE]) -> usize {
+        // encode padding
+        let mut padding_bytes = unsigned_varint::encode::u64_buffer();
+        let padding = unsigned_varint::encode::u64(self.padding_size, &mut padding_bytes);
+        digest[0..padding.len()].copy_from_slice(padding);
+
+        // set the tree height
+        digest[padding.len()] = self.height as u8;
+
+        // copy the root hash
+        digest[padding.len() + 1..][..NODE_SIZE].copy_from_slice(&self.root.0);
+
+        padding.len() + 1 + NODE_SIZE
+    }
+}
+
+/// Builder of a `Piece` created with `Piece::builder`. Root defaults to the
+/// zero commitment and padding defaults to zero.
+#[derive(Clone, Copy, Debug)]
+pub struct PieceBuilder {
+    height: usize,
+    root: Option<MerkleTreeNode>,
+    size: Size,
+}
+
+// Padding can be set directly or derived from the payload size.
+#[derive(Clone, Copy, Debug)]
+enum Size {
+    Padding(u64),
+    Payload(u64),
+}
+
+impl PieceBuilder {
+    pub fn root(&mut self, root: MerkleTreeNode) -> &mut Self {
+        self.root = Some(root);
+        self
+    }
+
+    /// Number of 0-bytes payload was padded by, replaces the payload size if
+    /// it was set.
+    pub fn padding_size(&mut self, padding_size: u64) -> &mut Self {
+        self.size = Size::Padding(padding_size);
+        self
+    }
+
+    /// Payload size in bytes, replaces the padding size if it was set.
+    pub fn payload_size(&mut self, payload_size: u64) -> &mut Self {
+        self.size = Size::Payload(payload_size);
+        self
+    }
+
+    /// Creates the piece if the hasher could produce a tree of the given
+    /// height and the payload fits in it.
+    pub fn build(&self) -> Result<Piece, PieceHasherError> {
+        // Height is checked first, as the default root is only available for
+        // the heights in range.
+        let mut piece = Piece::from_parts(MerkleTreeNode::empty(), self.height, 0);
+        piece.validate()?;
+        piece.root = match self.root {
+            Some(root) => root,
+            None => from_level(self.height).unwrap(),
+        };
+        piece.padding_size = match self.size {
+            Size::Padding(padding_size) => padding_size,
+            Size::Payload(payload_size) if payload_size <= piece.capacity() => {
+                piece.capacity() - payload_size
+            }
+            Size::Payload(payload_size) => {
+                return Err(PieceHasherError::InvalidPiece(format!(
+                    "Payload of {} bytes exceeds the capacity of {} bytes",
+                    payload_size,
+                    piece.capacity()
+                )))
+            }
+        };
+        piece.validate()?;
+        Ok(piece)
+    }
+}
+
+/// Decodes the piece from the multihash digest (padding varint, height and
+/// root). Digest must use the minimal varint encoding and describe a tree
+/// the hasher could produce, with padding not exceeding its capacity.
+impl TryFrom<&Multihash> for Piece {
+    type Error = PieceHasherError;
+    fn try_from(multihash: &Multihash) -> Result<Self, Self::Error> {
+        if multihash.code() != CODE {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Unexpected multihash code 0x{:x}",
+                multihash.code()
+            )));
+        }
+
+        let digest = multihash.digest();
+        let (padding_size, rest) = unsigned_varint::decode::u64(digest)
+            .map_err(|error| PieceHasherError::InvalidPiece(error.to_string()))?;
+        if rest.len() != HEIGHT_SIZE + ROOT_SIZE {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Unexpected digest size {}",
+                digest.len()
+            )));
+        }
+
+        let root = MerkleTreeNode(rest[HEIGHT_SIZE..].try_into().unwrap());
+        Piece::checked(root, rest[0] as usize, padding_size)
+    }
+}
+
+/// Decodes the piece from the CID v1 with a `raw` codec produced by
+/// `Piece::link`.
+impl TryFrom<&cid::Cid> for Piece {
+    type Error = PieceHasherError;
+    fn try_from(link: &cid::Cid) -> Result<Self, Self::Error> {
+        if link.version() != cid::Version::V1 || link.codec() != RAW as u64 {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Expected CID v1 with raw codec, got {}",
+                link
+            )));
+        }
+        Piece::try_from(link.hash())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Piece;
+    use crate::error::PieceHasherError;
+    use crate::hasher::{PieceHasher, CODE};
+    use multihash::Multihash;
+    use std::convert::TryFrom;
+
+    fn digest(padding: &[u8], height: u8) -> Multihash {
+        let mut digest = padding.to_vec();
+        digest.push(height);
+        digest.extend_from_slice(&[7u8; 32]);
+        Multihash::wrap(CODE, &digest).unwrap()
+    }
+
+    #[test]
+    fn test_try_from_multihash() {
+        for size in [0, 1, 127, 128, 127 * 4 + 1, 127 * 1000] {
+            let piece = PieceHasher::from(vec![3u8; size].as_slice()).piece();
+            assert_eq!(Piece::try_from(&piece.multihash()), Ok(piece));
+            assert_eq!(Piece::try_from(&piece.link()), Ok(piece));
+            assert_eq!(piece.payload_size(), size as u64);
+        }
+
+        let piece = Piece::try_from(&digest(&[248, 3], 5)).unwrap();
+        assert_eq!(piece.padding_size(), 504);
+        assert_eq!(piece.size(), 1024);
+        assert_eq!(piece.payload_size(), 1016 - 504);
+    }
+
+    #[test]
+    fn test_try_from_link() {
+        let link: cid::Cid = "bafkzcibd7abqlxticxolgseegik2stpfgkkuwyf6kufex3doorkvmzpjuxwe4dz4"
+            .parse()
+            .unwrap();
+        let piece = Piece::try_from(&link).unwrap();
+        assert_eq!(piece.height(), 5);
+        assert_eq!(piece.payload_size(), 128 * 4);
+        assert_eq!(piece.link(), link);
+
+        let dag_cbor = cid::Cid::new_v1(0x71, *link.hash());
+        assert!(Piece::try_from(&dag_cbor).is_err());
+    }
+
+    #[test]
+    fn test_try_from_rejects_invalid() {
+        let sha256 = Multihash::wrap(0x12, &[0u8; 32]).unwrap();
+        assert_eq!(
+            Piece::try_from(&sha256),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Unexpected multihash code 0x12"
+            )))
+        );
+
+        // Non minimal varint encoding of 0.
+        assert!(Piece::try_from(&digest(&[128, 0], 4)).is_err());
+        assert!(Piece::try_from(&digest(&[0], 1)).is_err());
+        assert!(Piece::try_from(&digest(&[0], 51)).is_err());
+        assert!(Piece::try_from(&digest(&[0], 50)).is_ok());
+        // Height 2 piece holds 127 bytes.
+        assert!(Piece::try_from(&digest(&[127], 2)).is_ok());
+        assert_eq!(
+            Piece::try_from(&digest(&[128, 1], 2)),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Padding of 128 bytes exceeds the capacity of 127 bytes"
+            )))
+        );
+
+        let truncated = Multihash::wrap(CODE, &[0, 4, 1, 2, 3]).unwrap();
+        assert!(Piece::try_from(&truncated).is_err());
+        let empty = Multihash::wrap(CODE, &[]).unwrap();
+        assert!(Piece::try_from(&empty).is_err());
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(Piece::try_new(2), Ok(Piece::new(2)));
+        assert_eq!(Piece::try_new(50), Ok(Piece::new(50)));
+        for height in [0, 1, 51, 64, 255] {
+            assert_eq!(
+                Piece::try_new(height),
+                Err(PieceHasherError::InvalidPiece(format!(
+                    "Height {} is outside of the 2..=50 range",
+                    height
+                )))
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let piece = PieceHasher::from(&[5u8; 300]).piece();
+        let built = Piece::builder(piece.height())
+            .root(piece.root())
+            .payload_size(300)
+            .build();
+        assert_eq!(built, Ok(piece));
+        let built = Piece::builder(piece.height())
+            .root(piece.root())
+            .payload_size(1)
+            .padding_size(piece.padding_size())
+            .build();
+        assert_eq!(built, Ok(piece));
+
+        assert_eq!(Piece::builder(7).build(), Ok(Piece::new(7)));
+        assert_eq!(
+            Piece::builder(2)
+                .padding_size(127)
+                .build()
+                .unwrap()
+                .payload_size(),
+            0
+        );
+        assert_eq!(
+            Piece::builder(2).padding_size(128).build(),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Padding of 128 bytes exceeds the capacity of 127 bytes"
+            )))
+        );
+        assert_eq!(
+            Piece::builder(3).payload_size(255).build(),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Payload of 255 bytes exceeds the capacity of 254 bytes"
+            )))
+        );
+        assert!(Piece::builder(0).root(piece.root()).build().is_err());
+    }
+
+    #[test]
+    fn test_hasher_try_from() {
+        let piece = Piece::new(4);
+        let mut hasher = PieceHasher::try_from(&piece).unwrap();
+        hasher.try_update(&[0u8; 127 * 4]).unwrap();
+        assert_eq!(hasher.piece(), Piece::new(5));
+
+        // Pieces that wrap payload size or have no tree are rejected.
+        let mut invalid = Piece::new(2);
+        invalid.with_padding_size(200);
+        assert!(PieceHasher::try_from(&invalid).is_err());
+        assert!(PieceHasher::try_from(&Piece::new(0)).is_err());
+        assert!(PieceHasher::try_from(&Piece::new(51)).is_err());
+    }
+
+    #[test]
+    fn test_join() {
+        let left: Vec<u8> = (0..127 * 4 - 10).map(|i| i as u8).collect();
+        let right: Vec<u8> = (0..127 * 3).map(|i| (i * 3) as u8).collect();
+        let joined = Piece::join(
+            &PieceHasher::from(left.as_slice()).piece(),
+            &PieceHasher::from(right.as_slice()).piece(),
+        )
+        .unwrap();
+
+        // Padding of the left piece is hashed as payload.
+        let mut bytes = left.clone();
+        bytes.resize(127 * 4, 0);
+        bytes.extend_from_slice(&right);
+        assert_eq!(joined, PieceHasher::from(bytes.as_slice()).piece());
+        assert_eq!(joined.payload_size(), 127 * 7);
+        assert_eq!(joined.padding_size(), 127);
+
+        let zero = Piece::join(&Piece::new(10), &Piece::new(10)).unwrap();
+        assert_eq!(zero, Piece::new(11));
+    }
+
+    #[test]
+    fn test_join_rejects_invalid() {
+        assert_eq!(
+            Piece::join(&Piece::new(4), &Piece::new(5)),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Can not join pieces of heights 4 and 5"
+            )))
+        );
+        assert_eq!(
+            Piece::join(&Piece::new(50), &Piece::new(50)),
+            Err(PieceHasherError::LevelOutOfRange { level: 51, max: 50 })
+        );
+
+        // Inputs the hasher could not produce are rejected.
+        assert_eq!(
+            Piece::join(&Piece::new(1), &Piece::new(1)),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Height 1 is outside of the 2..=50 range"
+            )))
+        );
+        let mut overpadded = Piece::new(2);
+        overpadded.with_padding_size(128);
+        assert_eq!(
+            Piece::join(&Piece::new(2), &overpadded),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Padding of 128 bytes exceeds the capacity of 127 bytes"
+            )))
+        );
+        assert!(Piece::join(&overpadded, &Piece::new(2)).is_err());
+    }
+
+    #[test]
+    fn test_pad_to_height() {
+        let payload: Vec<u8> = (0..127 * 3 + 5).map(|i| (i * 7) as u8).collect();
+        let piece = PieceHasher::from(payload.as_slice()).piece();
+        assert_eq!(piece.pad_to_height(piece.height()), Ok(piece));
+
+        let padded = piece.pad_to_height(7).unwrap();
+        assert_eq!(padded.height(), 7);
+        assert_eq!(padded.payload_size(), piece.payload_size());
+        assert_eq!(padded.root(), padded.pad_to_height(7).unwrap().root());
+
+        // Hashing the payload followed by enough zeros to need the same
+        // height produces the same root.
+        let mut bytes = payload.clone();
+        bytes.resize(127 * 16 + 1, 0);
+        let hashed = PieceHasher::from(bytes.as_slice()).piece();
+        assert_eq!(hashed.height(), 7);
+        assert_eq!(hashed.root(), padded.root());
+
+        let zero = Piece::join(&piece.pad_to_height(6).unwrap(), &Piece::new(6)).unwrap();
+        assert_eq!(zero.root(), padded.root());
+
+        assert!(piece.pad_to_height(piece.height() - 1).is_err());
+        assert_eq!(
+            piece.pad_to_height(51),
+            Err(PieceHasherError::LevelOutOfRange { level: 51, max: 50 })
+        );
+        assert!(piece.pad_to_height(50).is_ok());
+
+        let mut overpadded = Piece::new(2);
+        overpadded.with_padding_size(128);
+        assert_eq!(
+            overpadded.pad_to_height(3),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Padding of 128 bytes exceeds the capacity of 127 bytes"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_link_v1() {
+        // Well known CommP of the 128 byte zero piece.
+        let piece = PieceHasher::from(&[0u8; 127]).piece();
+        let (link, size) = piece.link_v1();
+        assert_eq!(
+            link.to_string(),
+            "baga6ea4seaqdomn3tgwgrh3g532zopskstnbrd2n3sxfqbze7rxt7vqn7veigmy"
+        );
+        assert_eq!(size, 128);
+        assert_eq!(Piece::from_v1(&link, size, 127), Ok(piece));
+
+        for size in [0, 1, 65, 128, 127 * 9, 127 * 1000 + 1] {
+            let piece = PieceHasher::from(vec![9u8; size].as_slice()).piece();
+            let (link, padded_size) = piece.link_v1();
+            assert_eq!(padded_size, piece.size());
+            assert_eq!(Piece::from_v1(&link, padded_size, size as u64), Ok(piece));
+            // Same root with a different payload size is a different piece.
+            let other = Piece::from_v1(&link, padded_size * 2, size as u64).unwrap();
+            assert_ne!(other.link(), piece.link());
+            assert_eq!(other.link_v1().0, link);
+        }
+    }
+
+    #[test]
+    fn test_from_v1_rejects_invalid() {
+        let (link, _) = PieceHasher::from(&[1u8; 300]).piece().link_v1();
+        for size in [0, 64, 100, 1000, 1 << 60] {
+            assert!(Piece::from_v1(&link, size, 10).is_err(), "size {}", size);
+        }
+        assert_eq!(
+            Piece::from_v1(&link, 512, 509),
+            Err(PieceHasherError::InvalidPiece(String::from(
+                "Payload of 509 bytes exceeds the capacity of 508 bytes"
+            )))
+        );
+        assert!(Piece::from_v1(&link, 512, 508).is_ok());
+
+        let v2 = PieceHasher::from(&[1u8; 300]).link();
+        assert!(Piece::from_v1(&v2, 512, 300).is_err());
+        let raw = cid::Cid::new_v1(0x55, *link.hash());
+        assert!(Piece::from_v1(&raw, 512, 300).is_err());
     }
 }
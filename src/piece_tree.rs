@@ -0,0 +1,486 @@
+use crate::constant::{IN_BYTES_PER_QUAD, NODE_SIZE, OUT_BYTES_PER_QUAD};
+use crate::error::PieceHasherError;
+use crate::hasher::{expand_quad, unpad_quad, PieceHasher};
+use crate::piece::Piece;
+use crate::proof::{fold_multi_proof, MultiProof, PaddingProof, ProofData, RangeProof};
+use crate::tree::{compute_layer, compute_node, MerkleTreeNode};
+use crate::zero_comm;
+use std::convert::{TryFrom, TryInto};
+use std::io;
+
+/// Merkle tree of a piece holding every node by level, where level `0` holds
+/// the fr32 padded leaves and level `height` holds the root. Nodes covering
+/// zero padding are included.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PieceTree {
+    layers: Vec<Vec<MerkleTreeNode>>,
+}
+
+impl PieceTree {
+    /// Height of the tree, which is the same as the height of the piece.
+    pub fn height(&self) -> usize {
+        self.layers.len() - 1
+    }
+
+    pub fn root(&self) -> MerkleTreeNode {
+        self.layers[self.height()][0]
+    }
+
+    /// Leaves of the tree, including ones of the zero padding.
+    pub fn leaves(&self) -> &[MerkleTreeNode] {
+        &self.layers[0]
+    }
+
+    /// Nodes of the given level ordered from left to right.
+    pub fn layer(&self, level: usize) -> Option<&[MerkleTreeNode]> {
+        self.layers.get(level).map(|layer| layer.as_slice())
+    }
+
+    /// Node at the given level and index within that level.
+    pub fn node(&self, level: usize, index: u64) -> Option<MerkleTreeNode> {
+        let layer = self.layers.get(level)?;
+        layer.get(usize::try_from(index).ok()?).copied()
+    }
+
+    /// Left and right children of the node at the given level and index.
+    /// Leaves have no children.
+    pub fn children(&self, level: usize, index: u64) -> Option<[MerkleTreeNode; 2]> {
+        if level == 0 {
+            return None;
+        }
+        let left = self.node(level - 1, index.checked_mul(2)?)?;
+        let right = self.node(level - 1, index * 2 + 1)?;
+        Some([left, right])
+    }
+
+    /// Produces inclusion proof for the node at the given level and index.
+    pub fn prove(&self, level: usize, index: u64) -> Result<ProofData, PieceHasherError> {
+        if self.node(level, index).is_none() {
+            return Err(PieceHasherError::NodeOutOfRange { level, index });
+        }
+
+        let mut path = Vec::with_capacity(self.height() - level);
+        let mut position = index;
+        for layer in &self.layers[level..self.height()] {
+            path.push(layer[(position ^ 1) as usize]);
+            position >>= 1;
+        }
+
+        Ok(ProofData::new(index, path))
+    }
+
+    /// Produces single proof for the nodes at the given `(level, index)`
+    /// positions. Positions are sorted and deduplicated, but they must not
+    /// cover each other.
+    pub fn prove_multi(&self, positions: &[(usize, u64)]) -> Result<MultiProof, PieceHasherError> {
+        let mut positions = positions.to_vec();
+        positions.sort_unstable();
+        positions.dedup();
+
+        let nodes = positions
+            .iter()
+            .map(|&(level, index)| {
+                self.node(level, index)
+                    .ok_or(PieceHasherError::NodeOutOfRange { level, index })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut path = vec![];
+        fold_multi_proof(&positions, &nodes, self.height(), |level, index| {
+            let node = self.node(level, index).unwrap();
+            path.push(node);
+            Ok(node)
+        })?;
+
+        Ok(MultiProof::new(positions, path))
+    }
+
+    /// Produces proof for the `start..end` range of the payload bytes. Given
+    /// piece must be the one this tree was built for and the range must be
+    /// within its payload, as zero padding is proven with `prove_padding`.
+    pub fn prove_range(
+        &self,
+        piece: &Piece,
+        start: u64,
+        end: u64,
+    ) -> Result<RangeProof, PieceHasherError> {
+        self.check_piece(piece)?;
+        if start >= end || end > piece.payload_size() {
+            return Err(PieceHasherError::InvalidRange { start, end });
+        }
+
+        let quad = IN_BYTES_PER_QUAD as u64;
+
+        let (first, last) = (start / quad, (end - 1) / quad);
+        let head = self.quad(first)[..(start % quad) as usize].to_vec();
+        let tail = self.quad(last)[(end - last * quad) as usize..].to_vec();
+
+        // Each quad produces two nodes on the first level, for every level we
+        // add siblings at the boundaries of the range if it is not aligned.
+        let mut path = vec![];
+        let (mut left, mut right) = (first * 2, last * 2 + 1);
+        for layer in &self.layers[1..self.height()] {
+            if left & 1 == 1 {
+                path.push(layer[left as usize - 1]);
+            }
+            if right & 1 == 0 {
+                path.push(layer[right as usize + 1]);
+            }
+            left >>= 1;
+            right >>= 1;
+        }
+
+        Ok(RangeProof::new(start, end, head, tail, path))
+    }
+
+    /// Produces proof that all the bytes after the given payload size are
+    /// zero padding.
+    pub fn prove_padding(&self, payload_size: u64) -> Result<PaddingProof, PieceHasherError> {
+        let quad = IN_BYTES_PER_QUAD as u64;
+        let quads = self.leaves().len() as u64 / 4;
+        if payload_size > quads * quad {
+            return Err(PieceHasherError::InvalidRange {
+                start: payload_size,
+                end: quads * quad,
+            });
+        }
+        if payload_size == quads * quad {
+            return Ok(PaddingProof::new(vec![], vec![]));
+        }
+
+        let mut index = payload_size / quad;
+        let head = self.quad(index)[..(payload_size % quad) as usize].to_vec();
+        // Each quad produces a node on the second level, from there we only
+        // need left siblings as right ones are zero padding.
+        let mut path = vec![];
+        for layer in &self.layers[2..self.height()] {
+            if index & 1 == 1 {
+                path.push(layer[index as usize - 1]);
+            }
+            index >>= 1;
+        }
+
+        Ok(PaddingProof::new(head, path))
+    }
+
+    /// Replaces payload bytes starting at the given offset and recomputes
+    /// only the leaves and ancestors covering them, returning the updated
+    /// piece. Given piece must be the one this tree was built for. Offset
+    /// must be aligned to a 127 byte quad and bytes must either cover whole
+    /// quads or end where the payload ends, so that the payload size (and
+    /// with it height and padding) stays the same.
+    pub fn replace(
+        &mut self,
+        piece: &Piece,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<Piece, PieceHasherError> {
+        self.check_piece(piece)?;
+
+        let quad = IN_BYTES_PER_QUAD as u64;
+        let end = offset.saturating_add(bytes.len() as u64);
+        let payload_size = piece.payload_size();
+        if !offset.is_multiple_of(quad)
+            || end > payload_size
+            || (!end.is_multiple_of(quad) && end != payload_size)
+        {
+            return Err(PieceHasherError::InvalidRange { start: offset, end });
+        }
+        if bytes.is_empty() {
+            return Ok(*piece);
+        }
+
+        // Partial quad at the end of the payload is zero padded same as
+        // when hashing.
+        let first = (offset / quad) as usize * 4;
+        for (index, chunk) in bytes.chunks(IN_BYTES_PER_QUAD).enumerate() {
+            let mut source = [0u8; IN_BYTES_PER_QUAD];
+            source[..chunk.len()].copy_from_slice(chunk);
+            let leaves = expand_quad(&source);
+            let at = first + index * 4;
+            for (leaf, node) in leaves
+                .chunks_exact(NODE_SIZE)
+                .zip(self.layers[0][at..at + 4].iter_mut())
+            {
+                node.0.copy_from_slice(leaf);
+            }
+        }
+
+        // Recompute the span of every level above the replaced leaves.
+        let quads = bytes.len().div_ceil(IN_BYTES_PER_QUAD);
+        let (mut start, mut last) = (first, first + quads * 4 - 1);
+        for level in 1..=self.height() {
+            let (below, above) = self.layers.split_at_mut(level);
+            start >>= 1;
+            last >>= 1;
+            compute_layer(
+                &below[level - 1][start * 2..last * 2 + 2],
+                &mut above[0][start..=last],
+            );
+        }
+
+        Ok(Piece::from_parts(
+            self.root(),
+            self.height(),
+            piece.padding_size(),
+        ))
+    }
+
+    // Checks that the tree was built for the given piece.
+    fn check_piece(&self, piece: &Piece) -> Result<(), PieceHasherError> {
+        if piece.height() != self.height() || piece.root() != self.root() {
+            return Err(PieceHasherError::InvalidTree(String::from(
+                "Root does not match the piece",
+            )));
+        }
+        Ok(())
+    }
+
+    // Payload bytes of the quad at the given index recovered from its leaves.
+    fn quad(&self, index: u64) -> [u8; IN_BYTES_PER_QUAD] {
+        let mut leaves = [0u8; OUT_BYTES_PER_QUAD];
+        let first = index as usize * 4;
+        for (chunk, leaf) in leaves
+            .chunks_exact_mut(NODE_SIZE)
+            .zip(self.layers[0][first..first + 4].iter())
+        {
+            chunk.copy_from_slice(&leaf.0);
+        }
+        unpad_quad(&leaves)
+    }
+}
+
+/// Hasher that retains every node of the tree, so that it can be navigated
+/// once all the bytes are written.
+pub struct TreeHasher {
+    hasher: PieceHasher,
+}
+
+impl TreeHasher {
+    pub fn new() -> Self {
+        let mut hasher = PieceHasher::new();
+        hasher.retained = Some(vec![Vec::new()]);
+        TreeHasher { hasher }
+    }
+
+    pub fn try_update(&mut self, bytes: &[u8]) -> Result<(), PieceHasherError> {
+        self.hasher.try_update(bytes)
+    }
+
+    /// Returns the piece for the bytes written so far.
+    pub fn piece(&self) -> Piece {
+        self.hasher.piece()
+    }
+
+    /// Returns the piece for all the bytes written along with its tree.
+    pub fn finish(mut self) -> (Piece, PieceTree) {
+        let piece = self.hasher.piece();
+        let height = piece.height();
+        let mut layers = self.hasher.retained.take().unwrap();
+        layers.resize_with(height + 1, Vec::new);
+
+        // Partially written quad is zero padded same as when computing the
+        // root, note that the last complete quad is also kept in the buffer.
+        let offset = self.hasher.offset;
+        if offset > 0 || self.hasher.bytes_written == 0 {
+            let mut quad = self.hasher.buffer;
+            quad[offset..].fill(0);
+            for leaf in expand_quad(&quad).chunks_exact(NODE_SIZE) {
+                layers[0].push(MerkleTreeNode(leaf.try_into().unwrap()));
+            }
+        }
+
+        // Each layer holds the nodes that were complete while hashing. The
+        // rest are either computed from the layer below when they cover the
+        // payload or are zero commitments when they only cover the padding.
+        let mut width = layers[0].len();
+        layers[0].resize(1 << height, zero_comm::from_level(0).unwrap());
+        for level in 1..=height {
+            let (below, above) = layers.split_at_mut(level);
+            let (below, layer) = (&below[level - 1], &mut above[0]);
+            width = width.div_ceil(2);
+            for index in layer.len()..width {
+                layer.push(compute_node(&below[2 * index], &below[2 * index + 1]));
+            }
+            layer.resize(1 << (height - level), zero_comm::from_level(level).unwrap());
+        }
+
+        (piece, PieceTree { layers })
+    }
+}
+
+impl Default for TreeHasher {
+    fn default() -> Self {
+        TreeHasher::new()
+    }
+}
+
+impl io::Write for TreeHasher {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.try_update(bytes)?;
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{PieceTree, TreeHasher};
+    use crate::constant::NODE_SIZE;
+    use crate::error::PieceHasherError;
+    use crate::hasher::{expand_quad, PieceHasher};
+    use crate::piece::Piece;
+    use crate::tree::compute_node;
+    use crate::zero_comm;
+    use std::convert::TryInto;
+
+    // Payload of the given size, also used by the tests of other modules.
+    pub(crate) fn payload(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    // Piece and retained tree of the `payload` of the given size.
+    pub(crate) fn tree(size: usize) -> (Piece, PieceTree) {
+        let mut hasher = TreeHasher::new();
+        hasher.try_update(&payload(size)).unwrap();
+        hasher.finish()
+    }
+
+    fn build(bytes: &[u8]) -> PieceTree {
+        let mut hasher = TreeHasher::new();
+        hasher.try_update(bytes).unwrap();
+        let (piece, tree) = hasher.finish();
+        assert_eq!(piece, PieceHasher::from(bytes).piece());
+        assert_eq!(tree.root(), piece.root());
+        assert_eq!(tree.height(), piece.height());
+        tree
+    }
+
+    #[test]
+    fn test_matches_hasher() {
+        for size in [
+            0,
+            1,
+            65,
+            126,
+            127,
+            128,
+            127 * 2,
+            127 * 3 + 5,
+            127 * 8,
+            127 * 16 + 1,
+            127 * 2048 + 3,
+        ] {
+            let tree = build(&payload(size));
+            for level in 1..=tree.height() {
+                let layer = tree.layer(level).unwrap();
+                assert_eq!(layer.len(), 1 << (tree.height() - level));
+                for (index, node) in layer.iter().enumerate() {
+                    let [left, right] = tree.children(level, index as u64).unwrap();
+                    assert_eq!(*node, compute_node(&left, &right));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_leaves() {
+        let bytes = payload(127 * 2 + 10);
+        let tree = build(&bytes);
+        assert_eq!(tree.leaves().len(), 16);
+
+        let mut quad = [0u8; 127];
+        quad[..10].copy_from_slice(&bytes[127 * 2..]);
+        let leaves = expand_quad(&quad);
+        for (index, leaf) in leaves.chunks_exact(NODE_SIZE).enumerate() {
+            assert_eq!(tree.node(0, 8 + index as u64).unwrap().0, leaf);
+        }
+
+        let first = expand_quad(bytes[..127].try_into().unwrap());
+        assert_eq!(tree.node(0, 0).unwrap().0, first[..NODE_SIZE]);
+    }
+
+    #[test]
+    fn test_padding_nodes() {
+        // Three quads are padded to four, so last quad is all zero padding.
+        let tree = build(&payload(127 * 3));
+        assert_eq!(tree.height(), 4);
+        assert_eq!(tree.node(2, 3), Some(zero_comm::from_level(2).unwrap()));
+        assert_eq!(tree.node(1, 7), Some(zero_comm::from_level(1).unwrap()));
+        assert_eq!(tree.node(0, 15), Some(zero_comm::from_level(0).unwrap()));
+        assert_ne!(tree.node(2, 2), Some(zero_comm::from_level(2).unwrap()));
+
+        assert_eq!(tree.node(2, 4), None);
+        assert_eq!(tree.node(5, 0), None);
+        assert_eq!(tree.children(0, 0), None);
+    }
+
+    #[test]
+    fn test_io_write() {
+        let bytes = payload(127 * 5 + 3);
+        let mut hasher = TreeHasher::new();
+        std::io::copy(&mut bytes.as_slice(), &mut hasher).unwrap();
+        let (piece, tree) = hasher.finish();
+        assert_eq!(piece, PieceHasher::from(bytes.as_slice()).piece());
+        assert_eq!(tree, build(&bytes));
+    }
+
+    #[test]
+    fn test_replace() {
+        for (size, offset, length) in [
+            (127 * 8, 0, 127),
+            (127 * 8, 127 * 3, 127 * 2),
+            (127 * 8, 0, 127 * 8),
+            (127 * 5 + 9, 127 * 5, 9),
+            (127 * 5 + 9, 127 * 2, 127 * 3 + 9),
+            (127 * 2048 + 3, 127 * 1000, 127 * 7),
+            (100, 0, 100),
+        ] {
+            let mut bytes = payload(size);
+            let mut tree = build(&bytes);
+            let piece = PieceHasher::from(bytes.as_slice()).piece();
+
+            for byte in &mut bytes[offset..offset + length] {
+                *byte = !*byte;
+            }
+            let updated = tree
+                .replace(&piece, offset as u64, &bytes[offset..offset + length])
+                .unwrap();
+            assert_eq!(updated, PieceHasher::from(bytes.as_slice()).piece());
+            assert_eq!(
+                updated.multihash(),
+                PieceHasher::from(bytes.as_slice()).multihash()
+            );
+            assert_eq!(tree, build(&bytes));
+        }
+    }
+
+    #[test]
+    fn test_replace_rejects_invalid() {
+        let bytes = payload(127 * 5 + 9);
+        let mut tree = build(&bytes);
+        let piece = PieceHasher::from(bytes.as_slice()).piece();
+        let quad = [1u8; 127];
+
+        let error = PieceHasherError::InvalidRange { start: 1, end: 128 };
+        assert_eq!(tree.replace(&piece, 1, &quad), Err(error));
+        // Changing the payload size is rejected even if height would stay
+        // the same.
+        assert!(tree.replace(&piece, 127 * 5, &quad).is_err());
+        assert!(tree.replace(&piece, 127 * 5, &quad[..8]).is_err());
+        assert!(tree.replace(&piece, 127 * 6, &[]).is_err());
+        assert!(tree.replace(&piece, 0, &quad[..5]).is_err());
+
+        let other = PieceHasher::from(&quad).piece();
+        assert!(tree.replace(&other, 0, &quad).is_err());
+        assert_eq!(tree, build(&bytes));
+
+        assert_eq!(tree.replace(&piece, 127, &[]), Ok(piece));
+        let updated = tree.replace(&piece, 0, &quad).unwrap();
+        // Piece before the update no longer matches the tree.
+        assert!(tree.replace(&piece, 0, &quad).is_err());
+        assert_eq!(tree.replace(&updated, 0, &quad), Ok(updated));
+    }
+}
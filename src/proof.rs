@@ -0,0 +1,1064 @@
+use crate::constant::{IN_BYTES_PER_QUAD, NODE_SIZE};
+use crate::error::PieceHasherError;
+use crate::hasher::read_quad;
+use crate::piece::Piece;
+use crate::size::MIN_HEIGHT;
+use crate::tree::{compute_layer, compute_node, MerkleTreeNode};
+use crate::zero_comm;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+
+// CBOR major types used by the proof encodings.
+const CBOR_UINT: u8 = 0;
+const CBOR_BYTES: u8 = 2;
+const CBOR_ARRAY: u8 = 4;
+
+/// Inclusion proof of a node in the piece tree. It has the same shape as the
+/// `ProofData` of Filecoin data-segment tooling, where `index` is the index
+/// of the node within its level and `path` holds the siblings of the node and
+/// its ancestors starting from the bottom of the tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProofData {
+    index: u64,
+    path: Vec<MerkleTreeNode>,
+}
+
+impl ProofData {
+    pub fn new(index: u64, path: Vec<MerkleTreeNode>) -> Self {
+        ProofData { index, path }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+    pub fn path(&self) -> &[MerkleTreeNode] {
+        &self.path
+    }
+
+    /// Number of levels between the proven node and the root.
+    pub fn depth(&self) -> usize {
+        self.path.len()
+    }
+
+    /// Checks that the `node` at the given `level` is included in the tree
+    /// with the given `root`.
+    pub fn verify(
+        &self,
+        root: &MerkleTreeNode,
+        node: &MerkleTreeNode,
+        level: usize,
+    ) -> Result<(), PieceHasherError> {
+        verify_inclusion(root, node, level, self.index, &self.path)
+    }
+
+    /// Encodes the proof as a DAG-CBOR tuple `[path, index]` matching the
+    /// `ProofData` encoding of Filecoin data-segment tooling.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + 9 + self.path.len() * (NODE_SIZE + 2) + 9);
+        write_header(&mut bytes, CBOR_ARRAY, 2);
+        write_header(&mut bytes, CBOR_ARRAY, self.path.len() as u64);
+        for node in self.path.iter() {
+            write_header(&mut bytes, CBOR_BYTES, NODE_SIZE as u64);
+            bytes.extend_from_slice(&node.0);
+        }
+        write_header(&mut bytes, CBOR_UINT, self.index);
+        bytes
+    }
+
+    /// Decodes the proof from the bytes produced by `ProofData::to_bytes`.
+    /// Encodings that are not canonical are rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PieceHasherError> {
+        let mut cursor = 0;
+        if read_header(bytes, &mut cursor, CBOR_ARRAY)? != 2 {
+            return Err(invalid_proof("Expected a tuple of path and index"));
+        }
+
+        let length = read_header(bytes, &mut cursor, CBOR_ARRAY)?;
+        if length >= zero_comm::MAX_LEVEL as u64 {
+            return Err(PieceHasherError::InvalidProof(format!(
+                "Path of {} nodes exceeds the maximum tree height",
+                length
+            )));
+        }
+
+        let mut path = Vec::with_capacity(length as usize);
+        for _ in 0..length {
+            if read_header(bytes, &mut cursor, CBOR_BYTES)? != NODE_SIZE as u64 {
+                return Err(invalid_proof("Path node must be 32 bytes"));
+            }
+            let node = read_bytes(bytes, &mut cursor, NODE_SIZE)?;
+            path.push(MerkleTreeNode(node.try_into().unwrap()));
+        }
+
+        let index = read_header(bytes, &mut cursor, CBOR_UINT)?;
+        if cursor != bytes.len() {
+            return Err(invalid_proof("Proof has trailing bytes"));
+        }
+
+        Ok(ProofData { index, path })
+    }
+}
+
+/// Checks that the `node` at the given `level` and `index` within that level
+/// is included in the tree with the given `root`, by recomputing the root
+/// from the `path` of siblings ordered from the bottom of the tree.
+pub fn verify_inclusion(
+    root: &MerkleTreeNode,
+    node: &MerkleTreeNode,
+    level: usize,
+    index: u64,
+    path: &[MerkleTreeNode],
+) -> Result<(), PieceHasherError> {
+    let height = level + path.len();
+    if height >= zero_comm::MAX_LEVEL {
+        return Err(PieceHasherError::LevelOutOfRange {
+            level: height,
+            max: zero_comm::MAX_LEVEL - 1,
+        });
+    }
+    // Each path node determines one bit of the index.
+    if index.checked_shr(path.len() as u32).unwrap_or(0) != 0 {
+        return Err(PieceHasherError::InvalidProof(format!(
+            "Index {} is out of range for a path of {} nodes",
+            index,
+            path.len()
+        )));
+    }
+
+    if compute_root(node, index, path) != *root {
+        return Err(invalid_proof("Computed root does not match"));
+    }
+    Ok(())
+}
+
+/// Computes the root of the tree from the node at the given index and the
+/// path of its siblings ordered from the bottom of the tree.
+pub fn compute_root(node: &MerkleTreeNode, index: u64, path: &[MerkleTreeNode]) -> MerkleTreeNode {
+    let mut node = *node;
+    let mut index = index;
+    for sibling in path.iter() {
+        node = if index & 1 == 0 {
+            compute_node(&node, sibling)
+        } else {
+            compute_node(sibling, &node)
+        };
+        index >>= 1;
+    }
+    node
+}
+
+/// Proof that bytes are at the given range of the piece payload. Leaves are
+/// derived from whole 127 byte quads, so the proof carries bytes of the first
+/// and last quads that are outside of the range (`head` and `tail`) and the
+/// siblings of the minimal set of aligned subtrees covering those quads,
+/// ordered from the bottom of the tree with left sibling before the right
+/// one on every level.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RangeProof {
+    start: u64,
+    end: u64,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+    path: Vec<MerkleTreeNode>,
+}
+
+impl RangeProof {
+    pub fn new(
+        start: u64,
+        end: u64,
+        head: Vec<u8>,
+        tail: Vec<u8>,
+        path: Vec<MerkleTreeNode>,
+    ) -> Self {
+        RangeProof {
+            start,
+            end,
+            head,
+            tail,
+            path,
+        }
+    }
+
+    /// Payload offset of the first byte in the range.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+    /// Payload offset right after the last byte in the range.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+    /// Bytes of the first quad that precede the range.
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+    /// Bytes of the last quad that follow the range, including zero padding.
+    pub fn tail(&self) -> &[u8] {
+        &self.tail
+    }
+    pub fn path(&self) -> &[MerkleTreeNode] {
+        &self.path
+    }
+
+    /// Checks that `bytes` are at the range of the payload of the `piece`.
+    pub fn verify(&self, piece: &Piece, bytes: &[u8]) -> Result<(), PieceHasherError> {
+        let (start, end) = (self.start, self.end);
+        if start >= end || end > piece.payload_size() {
+            return Err(PieceHasherError::InvalidRange { start, end });
+        }
+        if bytes.len() as u64 != end - start {
+            return Err(PieceHasherError::InvalidProof(format!(
+                "Expected {} bytes for range {}..{} but got {}",
+                end - start,
+                start,
+                end,
+                bytes.len()
+            )));
+        }
+
+        let quad = IN_BYTES_PER_QUAD as u64;
+        if self.head.len() as u64 != start % quad
+            || self.tail.len() as u64 != (quad - end % quad) % quad
+        {
+            return Err(invalid_proof("Range is not extended to whole quads"));
+        }
+
+        let mut payload = Vec::with_capacity(self.head.len() + bytes.len() + self.tail.len());
+        payload.extend_from_slice(&self.head);
+        payload.extend_from_slice(bytes);
+        payload.extend_from_slice(&self.tail);
+
+        let mut nodes = Vec::with_capacity(payload.len() / IN_BYTES_PER_QUAD * 2);
+        for quad in payload.chunks_exact(IN_BYTES_PER_QUAD) {
+            nodes.extend_from_slice(&read_quad(quad.try_into().unwrap()));
+        }
+
+        // Every quad produces two nodes on the first level.
+        let index = start / quad * 2;
+        let root = compute_range_root(nodes, 1, index, piece.height(), &self.path)?;
+        if root != piece.root() {
+            return Err(invalid_proof("Computed root does not match"));
+        }
+        Ok(())
+    }
+}
+
+// Computes the root of the tree of the given height from the contiguous
+// `nodes` of the `level` starting at `index` and the boundary siblings.
+fn compute_range_root(
+    nodes: Vec<MerkleTreeNode>,
+    level: usize,
+    index: u64,
+    height: usize,
+    path: &[MerkleTreeNode],
+) -> Result<MerkleTreeNode, PieceHasherError> {
+    if height >= zero_comm::MAX_LEVEL || level > height {
+        return Err(PieceHasherError::LevelOutOfRange {
+            level: height,
+            max: zero_comm::MAX_LEVEL - 1,
+        });
+    }
+    if index + nodes.len() as u64 > 1 << (height - level) {
+        return Err(invalid_proof("Range is outside of the tree"));
+    }
+
+    let mut siblings = path.iter();
+    let mut sibling = || {
+        siblings
+            .next()
+            .copied()
+            .ok_or_else(|| invalid_proof("Proof is truncated"))
+    };
+    let (mut nodes, mut level, mut index) = (nodes, level, index);
+    while level < height {
+        let mut layer = Vec::with_capacity(nodes.len() + 2);
+        if index & 1 == 1 {
+            layer.push(sibling()?);
+            index -= 1;
+        }
+        layer.extend_from_slice(&nodes);
+        if layer.len() & 1 == 1 {
+            layer.push(sibling()?);
+        }
+
+        nodes = vec![MerkleTreeNode::empty(); layer.len() / 2];
+        compute_layer(&layer, &mut nodes);
+        index >>= 1;
+        level += 1;
+    }
+
+    if siblings.next().is_some() {
+        return Err(invalid_proof("Proof has unused nodes"));
+    }
+    Ok(nodes[0])
+}
+
+/// Proof that all the bytes of the piece after the payload are zero padding,
+/// confirming the payload size encoded in the piece digest. It holds payload
+/// bytes of the quad where padding starts and the left siblings of the path
+/// from that quad to the root. Right siblings on that path only cover the
+/// padding, so they are the zero commitments of the corresponding levels.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaddingProof {
+    head: Vec<u8>,
+    path: Vec<MerkleTreeNode>,
+}
+
+impl PaddingProof {
+    pub fn new(head: Vec<u8>, path: Vec<MerkleTreeNode>) -> Self {
+        PaddingProof { head, path }
+    }
+
+    /// Payload bytes of the quad where zero padding starts.
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+    pub fn path(&self) -> &[MerkleTreeNode] {
+        &self.path
+    }
+
+    /// Checks that the piece payload is followed by `padding_size()` zero
+    /// bytes.
+    pub fn verify(&self, piece: &Piece) -> Result<(), PieceHasherError> {
+        let height = piece.height();
+        if !(MIN_HEIGHT..zero_comm::MAX_LEVEL).contains(&height) {
+            return Err(PieceHasherError::LevelOutOfRange {
+                level: height,
+                max: zero_comm::MAX_LEVEL - 1,
+            });
+        }
+        if piece.padding_size() == 0 {
+            if !self.head.is_empty() || !self.path.is_empty() {
+                return Err(invalid_proof("Piece without padding has empty proof"));
+            }
+            return Ok(());
+        }
+
+        let payload = piece.payload_size();
+        let quad = IN_BYTES_PER_QUAD as u64;
+        if self.head.len() as u64 != payload % quad {
+            return Err(PieceHasherError::InvalidProof(format!(
+                "Expected {} bytes of the last quad but got {}",
+                payload % quad,
+                self.head.len()
+            )));
+        }
+
+        let mut bytes = [0u8; IN_BYTES_PER_QUAD];
+        bytes[..self.head.len()].copy_from_slice(&self.head);
+        let [left, right] = read_quad(&bytes);
+        // Each quad produces a node on the second level.
+        let mut node = compute_node(&left, &right);
+        let mut index = payload / quad;
+
+        let mut siblings = self.path.iter();
+        for level in 2..height {
+            node = if index & 1 == 1 {
+                let sibling = siblings
+                    .next()
+                    .ok_or_else(|| invalid_proof("Proof is truncated"))?;
+                compute_node(sibling, &node)
+            } else {
+                compute_node(&node, &zero_comm::from_level(level)?)
+            };
+            index >>= 1;
+        }
+
+        if siblings.next().is_some() {
+            return Err(invalid_proof("Proof has unused nodes"));
+        }
+        if node != piece.root() {
+            return Err(invalid_proof("Computed root does not match"));
+        }
+        Ok(())
+    }
+}
+
+/// Proof of inclusion for multiple nodes of the same tree, where each
+/// sibling is included only once and siblings that can be derived from the
+/// proven nodes are omitted. Positions are `(level, index)` pairs sorted in
+/// ascending order and siblings are ordered by level and then by index.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultiProof {
+    positions: Vec<(usize, u64)>,
+    path: Vec<MerkleTreeNode>,
+}
+
+impl MultiProof {
+    pub fn new(positions: Vec<(usize, u64)>, path: Vec<MerkleTreeNode>) -> Self {
+        MultiProof { positions, path }
+    }
+
+    pub fn positions(&self) -> &[(usize, u64)] {
+        &self.positions
+    }
+    pub fn path(&self) -> &[MerkleTreeNode] {
+        &self.path
+    }
+
+    /// Checks that `nodes` at the proof positions are included in the tree
+    /// with the given `root` and `height`.
+    pub fn verify(
+        &self,
+        root: &MerkleTreeNode,
+        height: usize,
+        nodes: &[MerkleTreeNode],
+    ) -> Result<(), PieceHasherError> {
+        if nodes.len() != self.positions.len() {
+            return Err(PieceHasherError::InvalidProof(format!(
+                "Expected {} nodes but got {}",
+                self.positions.len(),
+                nodes.len()
+            )));
+        }
+
+        let mut path = self.path.iter();
+        let computed = fold_multi_proof(&self.positions, nodes, height, |_, _| {
+            path.next()
+                .copied()
+                .ok_or_else(|| invalid_proof("Proof is truncated"))
+        })?;
+        if path.next().is_some() {
+            return Err(invalid_proof("Proof has unused nodes"));
+        }
+
+        if computed != *root {
+            return Err(invalid_proof("Computed root does not match"));
+        }
+        Ok(())
+    }
+
+    /// Encodes the proof as a CBOR tuple `[positions, path]`, where every
+    /// position is a `[level, index]` tuple.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            2 + 9 + self.positions.len() * 12 + 9 + self.path.len() * (NODE_SIZE + 2),
+        );
+        write_header(&mut bytes, CBOR_ARRAY, 2);
+        write_header(&mut bytes, CBOR_ARRAY, self.positions.len() as u64);
+        for &(level, index) in self.positions.iter() {
+            write_header(&mut bytes, CBOR_ARRAY, 2);
+            write_header(&mut bytes, CBOR_UINT, level as u64);
+            write_header(&mut bytes, CBOR_UINT, index);
+        }
+        write_header(&mut bytes, CBOR_ARRAY, self.path.len() as u64);
+        for node in self.path.iter() {
+            write_header(&mut bytes, CBOR_BYTES, NODE_SIZE as u64);
+            bytes.extend_from_slice(&node.0);
+        }
+        bytes
+    }
+
+    /// Decodes the proof from the bytes produced by `MultiProof::to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PieceHasherError> {
+        let mut cursor = 0;
+        if read_header(bytes, &mut cursor, CBOR_ARRAY)? != 2 {
+            return Err(invalid_proof("Expected a tuple of positions and path"));
+        }
+
+        // Every encoded item takes at least a byte, which bounds the lengths.
+        let length = read_header(bytes, &mut cursor, CBOR_ARRAY)?;
+        if length > (bytes.len() - cursor) as u64 {
+            return Err(invalid_proof("Proof is truncated"));
+        }
+        let mut positions = Vec::with_capacity(length as usize);
+        for _ in 0..length {
+            if read_header(bytes, &mut cursor, CBOR_ARRAY)? != 2 {
+                return Err(invalid_proof("Expected a tuple of level and index"));
+            }
+            let level = read_header(bytes, &mut cursor, CBOR_UINT)?;
+            if level >= zero_comm::MAX_LEVEL as u64 {
+                return Err(PieceHasherError::LevelOutOfRange {
+                    level: level.try_into().unwrap_or(usize::MAX),
+                    max: zero_comm::MAX_LEVEL - 1,
+                });
+            }
+            let index = read_header(bytes, &mut cursor, CBOR_UINT)?;
+            positions.push((level as usize, index));
+        }
+
+        let length = read_header(bytes, &mut cursor, CBOR_ARRAY)?;
+        if length > (bytes.len() - cursor) as u64 {
+            return Err(invalid_proof("Proof is truncated"));
+        }
+        let mut path = Vec::with_capacity(length as usize);
+        for _ in 0..length {
+            if read_header(bytes, &mut cursor, CBOR_BYTES)? != NODE_SIZE as u64 {
+                return Err(invalid_proof("Path node must be 32 bytes"));
+            }
+            let node = read_bytes(bytes, &mut cursor, NODE_SIZE)?;
+            path.push(MerkleTreeNode(node.try_into().unwrap()));
+        }
+
+        if cursor != bytes.len() {
+            return Err(invalid_proof("Proof has trailing bytes"));
+        }
+        Ok(MultiProof { positions, path })
+    }
+}
+
+/// Computes the root of the tree of given height from the `nodes` at sorted
+/// `positions`, level by level, combining known nodes and asking `sibling`
+/// for the rest in the order they are stored in `MultiProof`. Positions that
+/// are out of order, repeated or covered by other positions are rejected.
+pub(crate) fn fold_multi_proof<F>(
+    positions: &[(usize, u64)],
+    nodes: &[MerkleTreeNode],
+    height: usize,
+    mut sibling: F,
+) -> Result<MerkleTreeNode, PieceHasherError>
+where
+    F: FnMut(usize, u64) -> Result<MerkleTreeNode, PieceHasherError>,
+{
+    if positions.is_empty() {
+        return Err(invalid_proof("Proof has no nodes"));
+    }
+    if height >= zero_comm::MAX_LEVEL {
+        return Err(PieceHasherError::LevelOutOfRange {
+            level: height,
+            max: zero_comm::MAX_LEVEL - 1,
+        });
+    }
+    if positions.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(invalid_proof("Positions are not sorted or are repeated"));
+    }
+    for &(level, index) in positions.iter() {
+        if level > height || index >> (height - level) != 0 {
+            return Err(PieceHasherError::NodeOutOfRange { level, index });
+        }
+    }
+
+    let mut given = positions.iter().zip(nodes.iter()).peekable();
+    let mut known = BTreeMap::new();
+    let mut level = positions[0].0;
+    loop {
+        while let Some((&(_, index), node)) = given.next_if(|((at, _), _)| *at == level) {
+            if known.insert(index, *node).is_some() {
+                return Err(PieceHasherError::InvalidProof(format!(
+                    "Node {} of level {} is covered by other nodes",
+                    index, level
+                )));
+            }
+        }
+        if level == height {
+            return Ok(known[&0]);
+        }
+
+        let mut parents = BTreeMap::new();
+        let mut nodes = known.iter().peekable();
+        while let Some((&index, &node)) = nodes.next() {
+            let (left, right) = if index & 1 == 1 {
+                (sibling(level, index - 1)?, node)
+            } else if let Some((_, &right)) = nodes.next_if(|(next, _)| **next == index + 1) {
+                (node, right)
+            } else {
+                (node, sibling(level, index + 1)?)
+            };
+            parents.insert(index >> 1, compute_node(&left, &right));
+        }
+        known = parents;
+        level += 1;
+    }
+}
+
+/// Collects nodes required for the inclusion proofs of the target nodes as
+/// they are computed by the hasher, so that the tree does not need to be
+/// retained. Only the target nodes and their siblings are kept.
+#[derive(Clone, Debug)]
+pub(crate) struct ProofCollector {
+    targets: Vec<(usize, u64)>,
+    // Target node followed by the path of siblings for every target.
+    nodes: Vec<Vec<Option<MerkleTreeNode>>>,
+    // Slots in `nodes` that need to be filled by the node at given position.
+    wanted: HashMap<(usize, u64), Vec<(usize, usize)>>,
+    // Whether any of the wanted nodes is a leaf.
+    pub(crate) leaves: bool,
+}
+
+impl ProofCollector {
+    /// Creates collector for the `(level, index)` targets in trees of up to
+    /// the given height.
+    pub(crate) fn new(targets: &[(usize, u64)], max_height: usize) -> Self {
+        let mut nodes = Vec::with_capacity(targets.len());
+        let mut wanted: HashMap<_, Vec<_>> = HashMap::new();
+        for (target, &(level, index)) in targets.iter().enumerate() {
+            let depth = max_height.saturating_sub(level);
+            nodes.push(vec![None; depth + 1]);
+            wanted.entry((level, index)).or_default().push((target, 0));
+            for offset in 0..depth {
+                let sibling = (index >> offset) ^ 1;
+                let position = (level + offset, sibling);
+                wanted
+                    .entry(position)
+                    .or_default()
+                    .push((target, offset + 1));
+            }
+        }
+        let leaves = wanted.keys().any(|(level, _)| *level == 0);
+
+        ProofCollector {
+            targets: targets.to_vec(),
+            nodes,
+            wanted,
+            leaves,
+        }
+    }
+
+    /// Records the node at the given level and index if it is wanted.
+    pub(crate) fn observe(&mut self, level: usize, index: u64, node: &MerkleTreeNode) {
+        if let Some(slots) = self.wanted.get(&(level, index)) {
+            for &(target, slot) in slots.iter() {
+                self.nodes[target][slot] = Some(*node);
+            }
+        }
+    }
+
+    /// Forgets all the collected nodes.
+    pub(crate) fn clear(&mut self) {
+        for nodes in self.nodes.iter_mut() {
+            nodes.fill(None);
+        }
+    }
+
+    /// Returns proven nodes with their proofs in the order of the targets for
+    /// the tree of given height, where first `width` leaves hold the payload.
+    /// Nodes covering just the zero padding are never computed, so they are
+    /// filled with corresponding zero commitments.
+    pub(crate) fn proofs(
+        &self,
+        height: usize,
+        width: u64,
+    ) -> Result<Vec<(MerkleTreeNode, ProofData)>, PieceHasherError> {
+        let node = |level: usize, index: u64, node: Option<MerkleTreeNode>| match node {
+            Some(node) => node,
+            None => {
+                debug_assert!(index << level >= width);
+                zero_comm::from_level(level).unwrap()
+            }
+        };
+
+        let mut proofs = Vec::with_capacity(self.targets.len());
+        for (&(level, index), nodes) in self.targets.iter().zip(self.nodes.iter()) {
+            if level > height || index >> (height - level) != 0 {
+                return Err(PieceHasherError::NodeOutOfRange { level, index });
+            }
+
+            let path = (0..height - level)
+                .map(|offset| {
+                    let sibling = (index >> offset) ^ 1;
+                    node(level + offset, sibling, nodes[offset + 1])
+                })
+                .collect();
+            proofs.push((node(level, index, nodes[0]), ProofData::new(index, path)));
+        }
+
+        Ok(proofs)
+    }
+}
+
+fn invalid_proof(reason: &str) -> PieceHasherError {
+    PieceHasherError::InvalidProof(String::from(reason))
+}
+
+// Writes CBOR item header with the given major type using the shortest
+// encoding of the value.
+fn write_header(bytes: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        bytes.push(major | value as u8);
+    } else if value <= u8::MAX as u64 {
+        bytes.push(major | 24);
+        bytes.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        bytes.push(major | 25);
+        bytes.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        bytes.push(major | 26);
+        bytes.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        bytes.push(major | 27);
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+// Reads CBOR item header of the expected major type and returns its value.
+fn read_header(bytes: &[u8], cursor: &mut usize, major: u8) -> Result<u64, PieceHasherError> {
+    let initial = read_bytes(bytes, cursor, 1)?[0];
+    if initial >> 5 != major {
+        return Err(PieceHasherError::InvalidProof(format!(
+            "Expected CBOR major type {} but found {}",
+            major,
+            initial >> 5
+        )));
+    }
+
+    let (value, min) = match initial & 0b11111 {
+        info @ 0..=23 => return Ok(info as u64),
+        24 => (read_bytes(bytes, cursor, 1)?[0] as u64, 24),
+        25 => {
+            let value = read_bytes(bytes, cursor, 2)?;
+            (u16::from_be_bytes(value.try_into().unwrap()) as u64, 1 << 8)
+        }
+        26 => {
+            let value = read_bytes(bytes, cursor, 4)?;
+            (
+                u32::from_be_bytes(value.try_into().unwrap()) as u64,
+                1 << 16,
+            )
+        }
+        27 => {
+            let value = read_bytes(bytes, cursor, 8)?;
+            (u64::from_be_bytes(value.try_into().unwrap()), 1 << 32)
+        }
+        _ => return Err(invalid_proof("Unsupported CBOR item length")),
+    };
+
+    if value < min {
+        return Err(invalid_proof("CBOR integer is not minimally encoded"));
+    }
+    Ok(value)
+}
+
+fn read_bytes<'a>(
+    bytes: &'a [u8],
+    cursor: &mut usize,
+    length: usize,
+) -> Result<&'a [u8], PieceHasherError> {
+    if *cursor + length > bytes.len() {
+        return Err(invalid_proof("Proof is truncated"));
+    }
+    let slice = &bytes[*cursor..*cursor + length];
+    *cursor += length;
+    Ok(slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_inclusion, MultiProof, PaddingProof, ProofData, RangeProof};
+    use crate::error::PieceHasherError;
+    use crate::hasher::PieceHasher;
+    use crate::piece_tree::tests::{payload, tree};
+    use crate::tree::MerkleTreeNode;
+
+    #[test]
+    fn test_prove_every_node() {
+        let (_, tree) = tree(127 * 5 + 9);
+        let root = tree.root();
+        for level in 0..=tree.height() {
+            for index in 0..tree.layer(level).unwrap().len() as u64 {
+                let node = tree.node(level, index).unwrap();
+                let proof = tree.prove(level, index).unwrap();
+                assert_eq!(proof.depth(), tree.height() - level);
+                assert_eq!(proof.index(), index);
+                proof.verify(&root, &node, level).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        let (_, tree) = tree(127 * 4);
+        let root = tree.root();
+        let node = tree.node(1, 5).unwrap();
+        let proof = tree.prove(1, 5).unwrap();
+
+        let mismatch = Err(PieceHasherError::InvalidProof(String::from(
+            "Computed root does not match",
+        )));
+        let other = tree.node(1, 4).unwrap();
+        assert_eq!(proof.verify(&root, &other, 1), mismatch);
+        assert_eq!(verify_inclusion(&root, &node, 1, 4, proof.path()), mismatch);
+        assert_eq!(proof.verify(&node, &node, 1), mismatch);
+        assert!(verify_inclusion(&root, &node, 1, 5 + 8, proof.path()).is_err());
+        assert!(verify_inclusion(&root, &node, 62, 5, proof.path()).is_err());
+
+        assert_eq!(
+            tree.prove(1, 8),
+            Err(PieceHasherError::NodeOutOfRange { level: 1, index: 8 })
+        );
+        assert_eq!(
+            tree.prove(5, 0),
+            Err(PieceHasherError::NodeOutOfRange { level: 5, index: 0 })
+        );
+    }
+
+    #[test]
+    fn test_range_proofs() {
+        let size = 127 * 9 + 40;
+        let bytes = payload(size);
+        let (piece, tree) = tree(size);
+
+        let ranges = [
+            (0, 1),
+            (0, 127),
+            (0, size),
+            (5, 300),
+            (127, 254),
+            (126, 128),
+            (127 * 3 + 1, 127 * 7 - 1),
+            (size - 1, size),
+            (127 * 9, size),
+        ];
+        for (start, end) in ranges.iter().copied() {
+            let proof = tree.prove_range(&piece, start as u64, end as u64).unwrap();
+            assert_eq!(proof.head(), &bytes[start / 127 * 127..start]);
+            proof.verify(&piece, &bytes[start..end]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_range_proof_rejects_invalid() {
+        let size = 127 * 5 + 3;
+        let bytes = payload(size);
+        let (piece, tree) = tree(size);
+
+        let proof = tree.prove_range(&piece, 100, 400).unwrap();
+        proof.verify(&piece, &bytes[100..400]).unwrap();
+
+        let mut altered = bytes[100..400].to_vec();
+        altered[150] ^= 1;
+        assert!(proof.verify(&piece, &altered).is_err());
+        assert!(proof.verify(&piece, &bytes[101..401]).is_err());
+        assert!(proof.verify(&piece, &bytes[100..399]).is_err());
+
+        let shifted = RangeProof::new(
+            101,
+            401,
+            bytes[..101].to_vec(),
+            proof.tail()[1..].to_vec(),
+            proof.path().to_vec(),
+        );
+        assert!(shifted.verify(&piece, &bytes[100..400]).is_err());
+
+        let mut path = proof.path().to_vec();
+        path.push(MerkleTreeNode::empty());
+        let extra = RangeProof::new(100, 400, proof.head().to_vec(), proof.tail().to_vec(), path);
+        assert!(extra.verify(&piece, &bytes[100..400]).is_err());
+
+        // Range must be within payload even though tree holds padding bytes.
+        let padding = PieceHasherError::InvalidRange {
+            start: size as u64 - 1,
+            end: size as u64 + 10,
+        };
+        assert_eq!(
+            tree.prove_range(&piece, size as u64 - 1, size as u64 + 10),
+            Err(padding.clone())
+        );
+        let mut tail = bytes[size - 1..].to_vec();
+        tail.extend_from_slice(&[0u8; 10]);
+        let forged = RangeProof::new(
+            size as u64 - 1,
+            size as u64 + 10,
+            proof.head().to_vec(),
+            vec![],
+            proof.path().to_vec(),
+        );
+        assert_eq!(forged.verify(&piece, &tail), Err(padding));
+
+        assert!(tree.prove_range(&piece, 10, 10).is_err());
+        assert!(tree.prove_range(&piece, 0, 127 * 8 + 1).is_err());
+        assert_eq!(
+            tree.prove_range(&PieceHasher::new().piece(), 0, 10),
+            Err(PieceHasherError::InvalidTree(String::from(
+                "Root does not match the piece"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_multi_proofs() {
+        let (piece, tree) = tree(127 * 13 + 5);
+        let height = tree.height();
+
+        let sets: Vec<Vec<(usize, u64)>> = vec![
+            vec![(0, 0)],
+            vec![(0, 0), (0, 1)],
+            vec![(0, 3), (0, 17), (0, 40), (0, 63)],
+            vec![(0, 5), (1, 30), (3, 1), (4, 2)],
+            vec![(height, 0)],
+        ];
+        for positions in sets.iter() {
+            let proof = tree.prove_multi(positions).unwrap();
+            let nodes: Vec<_> = positions
+                .iter()
+                .map(|(level, index)| tree.node(*level, *index).unwrap())
+                .collect();
+            proof.verify(&piece.root(), height, &nodes).unwrap();
+            assert_eq!(MultiProof::from_bytes(&proof.to_bytes()), Ok(proof.clone()));
+
+            // Shared siblings are included only once.
+            let separate: usize = positions
+                .iter()
+                .map(|(level, index)| tree.prove(*level, *index).unwrap().depth())
+                .sum();
+            assert!(proof.path().len() <= separate);
+        }
+
+        // Whole level needs no siblings
+        let leaves: Vec<_> = (0..64).map(|index| (0, index)).collect();
+        assert!(tree.prove_multi(&leaves).unwrap().path().is_empty());
+
+        // Nodes above two neighbouring pairs of leaves share all siblings.
+        let proof = tree.prove_multi(&[(0, 0), (0, 2)]).unwrap();
+        assert_eq!(proof.path().len(), height);
+    }
+
+    #[test]
+    fn test_multi_proof_rejects_invalid() {
+        let (_, tree) = tree(127 * 6);
+        let (root, height) = (tree.root(), tree.height());
+        let node = |level, index| tree.node(level, index).unwrap();
+
+        let proof = tree.prove_multi(&[(0, 2), (1, 6)]).unwrap();
+        let nodes = [node(0, 2), node(1, 6)];
+        proof.verify(&root, height, &nodes).unwrap();
+
+        assert!(proof
+            .verify(&root, height, &[node(0, 3), node(1, 6)])
+            .is_err());
+        assert!(proof.verify(&root, height, &nodes[..1]).is_err());
+        assert!(proof.verify(&root, height + 1, &nodes).is_err());
+        assert!(proof.verify(&node(1, 6), height, &nodes).is_err());
+
+        let truncated = MultiProof::new(proof.positions().to_vec(), proof.path()[1..].to_vec());
+        assert!(truncated.verify(&root, height, &nodes).is_err());
+        let mut path = proof.path().to_vec();
+        path.push(node(0, 0));
+        let extra = MultiProof::new(proof.positions().to_vec(), path);
+        assert!(extra.verify(&root, height, &nodes).is_err());
+
+        // Unsorted, repeated and overlapping positions are ambiguous.
+        let unsorted = MultiProof::new(vec![(1, 6), (0, 2)], proof.path().to_vec());
+        assert!(unsorted
+            .verify(&root, height, &[node(1, 6), node(0, 2)])
+            .is_err());
+        let repeated = MultiProof::new(vec![(0, 2), (0, 2)], proof.path().to_vec());
+        assert!(repeated
+            .verify(&root, height, &[node(0, 2), node(0, 2)])
+            .is_err());
+        assert_eq!(
+            tree.prove_multi(&[(0, 2), (1, 1)]),
+            Err(PieceHasherError::InvalidProof(String::from(
+                "Node 1 of level 1 is covered by other nodes"
+            )))
+        );
+
+        assert_eq!(
+            tree.prove_multi(&[(0, 32)]),
+            Err(PieceHasherError::NodeOutOfRange {
+                level: 0,
+                index: 32
+            })
+        );
+        assert!(tree.prove_multi(&[]).is_err());
+
+        let bytes = proof.to_bytes();
+        assert!(MultiProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(MultiProof::from_bytes(&[0x82, 0x81, 0x82, 0x18, 0x40, 0x00, 0x80]).is_err());
+        assert!(MultiProof::from_bytes(&[0x82, 0x9a, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_padding_proofs() {
+        for size in [
+            0,
+            1,
+            126,
+            127,
+            128,
+            127 * 2,
+            127 * 3 + 7,
+            127 * 8,
+            127 * 9 - 1,
+        ] {
+            let bytes = payload(size);
+            let (piece, tree) = tree(size);
+
+            let proof = tree.prove_padding(piece.payload_size()).unwrap();
+            assert_eq!(proof.head(), &bytes[size / 127 * 127..]);
+            assert!(proof.path().len() < piece.height());
+            proof.verify(&piece).unwrap();
+
+            // Claiming a larger padding than the actual one fails.
+            if size > 0 {
+                let mut claimed = piece;
+                claimed.with_padding_size(piece.padding_size() + 1);
+                let proof = tree.prove_padding(claimed.payload_size()).unwrap();
+                assert!(proof.verify(&claimed).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_padding_proof_rejects_invalid() {
+        let (piece, tree) = tree(127 * 5 + 20);
+
+        let proof = tree.prove_padding(piece.payload_size()).unwrap();
+        proof.verify(&piece).unwrap();
+
+        let mut head = proof.head().to_vec();
+        head.push(0);
+        assert!(PaddingProof::new(head, proof.path().to_vec())
+            .verify(&piece)
+            .is_err());
+        let truncated = PaddingProof::new(proof.head().to_vec(), proof.path()[1..].to_vec());
+        assert!(truncated.verify(&piece).is_err());
+        let mut path = proof.path().to_vec();
+        path.push(MerkleTreeNode::empty());
+        assert!(PaddingProof::new(proof.head().to_vec(), path)
+            .verify(&piece)
+            .is_err());
+
+        // Full piece has no padding to prove.
+        let (full, full_tree) = self::tree(127 * 4);
+        let proof = full_tree.prove_padding(full.payload_size()).unwrap();
+        assert_eq!(proof, PaddingProof::new(vec![], vec![]));
+        proof.verify(&full).unwrap();
+
+        assert!(full_tree.prove_padding(127 * 4 + 1).is_err());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let path = vec![MerkleTreeNode([1u8; 32]), MerkleTreeNode([2u8; 32])];
+        let proof = ProofData::new(3, path);
+        let bytes = proof.to_bytes();
+
+        let mut expected = vec![0x82, 0x82, 0x58, 0x20];
+        expected.extend_from_slice(&[1u8; 32]);
+        expected.extend_from_slice(&[0x58, 0x20]);
+        expected.extend_from_slice(&[2u8; 32]);
+        expected.push(0x03);
+        assert_eq!(bytes, expected);
+        assert_eq!(ProofData::from_bytes(&bytes), Ok(proof));
+
+        let large = ProofData::new(1 << 40, vec![]);
+        assert_eq!(
+            large.to_bytes(),
+            vec![0x82, 0x80, 0x1b, 0, 0, 1, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(ProofData::from_bytes(&large.to_bytes()), Ok(large));
+    }
+
+    #[test]
+    fn test_decoding_rejects_malformed() {
+        let proof = ProofData::new(300, vec![MerkleTreeNode([7u8; 32])]);
+        let bytes = proof.to_bytes();
+        assert_eq!(ProofData::from_bytes(&bytes), Ok(proof));
+
+        // truncated
+        assert!(ProofData::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        // trailing bytes
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(ProofData::from_bytes(&trailing).is_err());
+        // node of a wrong size
+        let mut short = vec![0x82, 0x81, 0x58, 0x1f];
+        short.extend_from_slice(&[0u8; 31]);
+        short.push(0x00);
+        assert!(ProofData::from_bytes(&short).is_err());
+        // not minimally encoded index
+        assert!(ProofData::from_bytes(&[0x82, 0x80, 0x18, 0x05]).is_err());
+        // wrong type
+        assert!(ProofData::from_bytes(&[0xa2, 0x80, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use crate::constant::{IN_BYTES_PER_QUAD, OUT_BYTES_PER_QUAD};
+use crate::error::PieceHasherError;
+use crate::hasher::{CODE_SIZE, HEIGHT_SIZE, MAX_HEIGHT, MAX_PAYLOAD_SIZE, ROOT_SIZE};
+use crate::util::{from_height, varint_estimate};
+use std::convert::TryFrom;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+// Smallest piece holds a single quad.
+pub(crate) const MIN_HEIGHT: usize = 2;
+
+/// Size of the piece in bytes after fr32 padding, which is a power of two
+/// the hasher could produce.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PaddedPieceSize(u64);
+
+/// Number of payload bytes that fill the piece before fr32 padding, which
+/// is 127 bytes for every 128 bytes of the padded piece.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct UnpaddedPieceSize(u64);
+
+impl PaddedPieceSize {
+    pub fn new(size: u64) -> Result<Self, PieceHasherError> {
+        if !size.is_power_of_two()
+            || size < from_height(MIN_HEIGHT as u32)
+            || size > from_height(MAX_HEIGHT as u32)
+        {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Padded piece size {} is not a power of two between {} and {}",
+                size,
+                from_height(MIN_HEIGHT as u32),
+                from_height(MAX_HEIGHT as u32)
+            )));
+        }
+        Ok(PaddedPieceSize(size))
+    }
+
+    /// Size of the piece with the tree of the given height.
+    pub fn from_height(height: usize) -> Result<Self, PieceHasherError> {
+        if height < MIN_HEIGHT || height > MAX_HEIGHT as usize {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Height {} is outside of the {}..={} range",
+                height, MIN_HEIGHT, MAX_HEIGHT
+            )));
+        }
+        Ok(PaddedPieceSize(from_height(height as u32)))
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+
+    /// Height of the piece tree.
+    pub fn height(&self) -> usize {
+        (self.0 / from_height(0)).trailing_zeros() as usize
+    }
+
+    pub fn unpadded(&self) -> UnpaddedPieceSize {
+        UnpaddedPieceSize(unpadded_size(self.0))
+    }
+}
+
+impl UnpaddedPieceSize {
+    pub fn new(size: u64) -> Result<Self, PieceHasherError> {
+        let quad = IN_BYTES_PER_QUAD as u64;
+        if !size.is_multiple_of(quad) || !(size / quad).is_power_of_two() || size > MAX_PAYLOAD_SIZE
+        {
+            return Err(PieceHasherError::InvalidPiece(format!(
+                "Unpadded piece size {} is not 127 bytes times a power of two",
+                size
+            )));
+        }
+        Ok(UnpaddedPieceSize(size))
+    }
+
+    /// Smallest piece size that fits the payload of the given size.
+    pub fn fit(payload_size: u64) -> Result<Self, PieceHasherError> {
+        if payload_size > MAX_PAYLOAD_SIZE {
+            return Err(PieceHasherError::PayloadTooLarge);
+        }
+        Ok(UnpaddedPieceSize(
+            required_quads(payload_size) * IN_BYTES_PER_QUAD as u64,
+        ))
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+
+    pub fn padded(&self) -> PaddedPieceSize {
+        PaddedPieceSize(self.0 / IN_BYTES_PER_QUAD as u64 * OUT_BYTES_PER_QUAD as u64)
+    }
+}
+
+impl TryFrom<u64> for PaddedPieceSize {
+    type Error = PieceHasherError;
+    fn try_from(size: u64) -> Result<Self, Self::Error> {
+        PaddedPieceSize::new(size)
+    }
+}
+
+impl TryFrom<u64> for UnpaddedPieceSize {
+    type Error = PieceHasherError;
+    fn try_from(size: u64) -> Result<Self, Self::Error> {
+        UnpaddedPieceSize::new(size)
+    }
+}
+
+impl From<UnpaddedPieceSize> for PaddedPieceSize {
+    fn from(size: UnpaddedPieceSize) -> Self {
+        size.padded()
+    }
+}
+
+impl From<PaddedPieceSize> for UnpaddedPieceSize {
+    fn from(size: PaddedPieceSize) -> Self {
+        size.unpadded()
+    }
+}
+
+/// Number of payload bytes that fit in the piece of the given padded size.
+pub(crate) const fn unpadded_size(padded_size: u64) -> u64 {
+    padded_size / OUT_BYTES_PER_QUAD as u64 * IN_BYTES_PER_QUAD as u64
+}
+
+/// Number of quads in the smallest piece that fits the payload of the given
+/// size, which is a power of two and at least one quad.
+fn required_quads(payload_size: u64) -> u64 {
+    payload_size
+        .div_ceil(IN_BYTES_PER_QUAD as u64)
+        .next_power_of_two()
+}
+
+/// Calculates zero padding required before the given payload can be fr32
+/// padded. Payload must not exceed `MAX_PAYLOAD_SIZE`.
+pub(crate) fn required_zero_padding(payload_size: u64) -> u64 {
+    required_quads(payload_size) * IN_BYTES_PER_QUAD as u64 - payload_size
+}
+
+/// Sizes of the piece produced by hashing the payload of the given size,
+/// known before any bytes are hashed e.g. to plan deals or to allocate the
+/// buffer for `PieceHasher::digest_into`.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PieceSizes {
+    payload_size: u64,
+    unpadded_size: UnpaddedPieceSize,
+}
+
+impl PieceSizes {
+    pub fn new(payload_size: u64) -> Result<Self, PieceHasherError> {
+        Ok(PieceSizes {
+            payload_size,
+            unpadded_size: UnpaddedPieceSize::fit(payload_size)?,
+        })
+    }
+
+    pub fn payload_size(&self) -> u64 {
+        self.payload_size
+    }
+
+    /// Number of 0-bytes payload will be padded by.
+    pub fn padding_size(&self) -> u64 {
+        self.unpadded_size.bytes() - self.payload_size
+    }
+
+    pub fn unpadded_size(&self) -> UnpaddedPieceSize {
+        self.unpadded_size
+    }
+
+    pub fn padded_size(&self) -> PaddedPieceSize {
+        self.unpadded_size.padded()
+    }
+
+    pub fn height(&self) -> usize {
+        self.padded_size().height()
+    }
+
+    /// Number of bytes of the raw digest.
+    pub fn digest_size(&self) -> usize {
+        varint_estimate(self.padding_size()) + HEIGHT_SIZE + ROOT_SIZE
+    }
+
+    /// Number of bytes of the multihash including the code and digest size.
+    pub fn multihash_size(&self) -> usize {
+        let digest_size = self.digest_size();
+        CODE_SIZE + varint_estimate(digest_size as u64) + digest_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        required_quads, required_zero_padding, PaddedPieceSize, PieceSizes, UnpaddedPieceSize,
+    };
+    use crate::constant::FRS_PER_QUAD;
+    use crate::error::PieceHasherError;
+    use crate::hasher::{PieceHasher, MAX_HEIGHT, MAX_PAYLOAD_SIZE};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_conversions() {
+        let padded = PaddedPieceSize::new(1 << 35).unwrap();
+        assert_eq!(padded.height(), 30);
+        assert_eq!(padded.unpadded().bytes(), 34_091_302_912);
+        assert_eq!(UnpaddedPieceSize::from(padded).padded(), padded);
+        assert_eq!(PaddedPieceSize::from_height(30), Ok(padded));
+
+        let unpadded = UnpaddedPieceSize::new(127 * 4).unwrap();
+        assert_eq!(PaddedPieceSize::from(unpadded).bytes(), 512);
+        assert_eq!(UnpaddedPieceSize::try_from(508), Ok(unpadded));
+        assert_eq!(PaddedPieceSize::try_from(512), Ok(unpadded.padded()));
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        for size in [0, 32, 64, 100, 129, 1 << 56] {
+            assert!(PaddedPieceSize::new(size).is_err(), "size {}", size);
+        }
+        for size in [0, 1, 126, 128, 127 * 3, 127 << 49] {
+            assert!(UnpaddedPieceSize::new(size).is_err(), "size {}", size);
+        }
+        assert!(PaddedPieceSize::from_height(1).is_err());
+        assert!(PaddedPieceSize::from_height(51).is_err());
+        assert_eq!(
+            UnpaddedPieceSize::fit(MAX_PAYLOAD_SIZE + 1),
+            Err(PieceHasherError::PayloadTooLarge)
+        );
+        assert!(UnpaddedPieceSize::fit(MAX_PAYLOAD_SIZE).is_ok());
+    }
+
+    #[test]
+    fn test_padding() {
+        // Every quad holds four leaves.
+        assert_eq!(required_quads(128) * FRS_PER_QUAD as u64, 8);
+        assert_eq!(required_quads(127 * 4 + 1) * FRS_PER_QUAD as u64, 8 * 4);
+        assert_eq!(required_zero_padding(0), 127);
+        assert_eq!(required_zero_padding(1), 126);
+        assert_eq!(required_zero_padding(5), 122);
+        assert_eq!(required_zero_padding(11), 116);
+        assert_eq!(required_zero_padding(127), 0);
+        assert_eq!(required_zero_padding(128), 127 - 1);
+        assert_eq!(required_zero_padding(127 * 2 - 1), 1);
+        assert_eq!(required_zero_padding(127 * 2), 0);
+        assert_eq!(required_zero_padding(127 * 2 + 1), 127 * 2 - 1);
+        assert_eq!(required_zero_padding(127 * 3), 127);
+        assert_eq!(required_zero_padding(127 * 4), 0);
+        assert_eq!(required_zero_padding(127 * 4 + 10), 127 * 8 - 127 * 4 - 10);
+        assert_eq!(required_zero_padding(128 * 4), 504);
+    }
+
+    #[test]
+    fn test_sizes_around_powers_of_two() {
+        // Payloads just above `127 * 2^k` need a piece twice as large.
+        for k in 0..48 {
+            let quads = 127u64 << k;
+            for (payload_size, padded_size) in [
+                (quads - 1, 128 << k),
+                (quads, 128 << k),
+                (quads + 1, 128 << (k + 1)),
+            ] {
+                let sizes = PieceSizes::new(payload_size).unwrap();
+                assert_eq!(sizes.padded_size().bytes(), padded_size, "k = {}", k);
+                assert_eq!(
+                    sizes.padding_size(),
+                    padded_size / 128 * 127 - payload_size,
+                    "k = {}",
+                    k
+                );
+                assert_eq!(sizes.height(), sizes.padded_size().height());
+            }
+        }
+        assert_eq!(
+            PieceSizes::new(MAX_PAYLOAD_SIZE).unwrap().height(),
+            MAX_HEIGHT as usize
+        );
+    }
+
+    #[test]
+    fn test_matches_hasher() {
+        for size in [0, 1, 65, 127, 128, 127 * 4, 128 * 4, 127 * 1000 + 3] {
+            let sizes = PieceSizes::new(size).unwrap();
+            let hasher = PieceHasher::from(vec![1u8; size as usize].as_slice());
+            let piece = hasher.piece();
+
+            assert_eq!(sizes.payload_size(), piece.payload_size());
+            assert_eq!(sizes.padding_size(), piece.padding_size());
+            assert_eq!(sizes.height(), piece.height());
+            assert_eq!(sizes.padded_size().bytes(), piece.size());
+            assert_eq!(sizes.digest_size(), hasher.digest_size());
+            assert_eq!(sizes.multihash_size(), hasher.multihash_size());
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use crate::error::PieceHasherError;
+use crate::hasher::PieceHasher;
+use crate::piece::Piece;
+use futures_core::TryStream;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::{fmt, io};
+
+/// Max number of bytes hashed per poll, so that huge chunks do not block the
+/// executor for long.
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+/// Error of `hash_stream`, which is either the error produced by the stream
+/// or the one produced by the hasher.
+#[derive(Debug, Eq, PartialEq)]
+pub enum StreamError<E> {
+    Stream(E),
+    Hasher(PieceHasherError),
+}
+
+impl<E> From<PieceHasherError> for StreamError<E> {
+    fn from(error: PieceHasherError) -> Self {
+        StreamError::Hasher(error)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for StreamError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Stream(error) => error.fmt(f),
+            StreamError::Hasher(error) => error.fmt(f),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for StreamError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Stream(error) => Some(error),
+            StreamError::Hasher(error) => Some(error),
+        }
+    }
+}
+
+/// Hashes all the chunks of the given stream (e.g. body of a HTTP response)
+/// and returns resulting piece. First error produced by the stream is
+/// returned as is. Large chunks are hashed in slices yielding to the
+/// executor in between.
+pub async fn hash_stream<S, B>(mut stream: S) -> Result<Piece, StreamError<S::Error>>
+where
+    S: TryStream<Ok = B> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let mut hasher = PieceHasher::new();
+    while let Some(chunk) = poll_fn(|cx| Pin::new(&mut stream).try_poll_next(cx)).await {
+        let chunk = chunk.map_err(StreamError::Stream)?;
+        let mut slices = chunk.as_ref().chunks(MAX_CHUNK_SIZE).peekable();
+        while let Some(slice) = slices.next() {
+            hasher.try_update(slice)?;
+            if slices.peek().is_some() {
+                YieldNow(false).await;
+            }
+        }
+    }
+
+    Ok(hasher.piece())
+}
+
+/// Adapter implementing `AsyncWrite` for the wrapped hasher. It hashes at
+/// most `MAX_CHUNK_SIZE` bytes per poll, once that many bytes were hashed
+/// since the task last yielded it is woken up and `Pending` is returned so
+/// that huge writes do not block the executor.
+pub struct AsyncWriter<'a> {
+    hasher: &'a mut PieceHasher,
+    // Bytes hashed since the task last yielded.
+    polled: usize,
+}
+
+impl<'a> AsyncWriter<'a> {
+    pub fn new(hasher: &'a mut PieceHasher) -> Self {
+        AsyncWriter { hasher, polled: 0 }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, bytes: &[u8]) -> Poll<io::Result<usize>> {
+        if self.polled >= MAX_CHUNK_SIZE {
+            self.polled = 0;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        let size = bytes.len().min(MAX_CHUNK_SIZE - self.polled);
+        self.hasher.try_update(&bytes[..size])?;
+        self.polled += size;
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl futures_io::AsyncWrite for AsyncWriter<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bytes: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write(cx, bytes)
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for AsyncWriter<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bytes: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write(cx, bytes)
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future that is pending on the first poll, giving executor a chance to run
+/// other tasks.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::PieceHasherError;
+    use crate::stream::{hash_stream, AsyncWriter, StreamError, MAX_CHUNK_SIZE};
+    use crate::PieceHasher;
+    use futures::executor::block_on;
+    use futures::stream;
+    use futures::task::noop_waker;
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::Context;
+
+    fn payload() -> Vec<u8> {
+        (0..MAX_CHUNK_SIZE * 2 + 300)
+            .map(|n| (n % 241) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_stream() {
+        let payload = payload();
+        let chunks = vec![
+            &payload[..10],
+            &payload[10..MAX_CHUNK_SIZE * 2 + 20],
+            &payload[MAX_CHUNK_SIZE * 2 + 20..],
+        ];
+
+        let chunks = chunks.into_iter().map(Ok::<_, Infallible>);
+        let piece = block_on(hash_stream(stream::iter(chunks))).unwrap();
+        assert_eq!(
+            piece.multihash(),
+            PieceHasher::from(&payload[..]).multihash()
+        );
+    }
+
+    #[test]
+    fn test_hash_stream_error() {
+        let chunks = vec![
+            Ok(vec![1u8; 10]),
+            Err("Connection reset"),
+            Ok(vec![2u8; 10]),
+        ];
+        assert_eq!(
+            block_on(hash_stream(stream::iter(chunks))),
+            Err(StreamError::Stream("Connection reset"))
+        );
+
+        assert_eq!(
+            StreamError::<Infallible>::from(PieceHasherError::PayloadTooLarge).to_string(),
+            "Payload size exceeded"
+        );
+    }
+
+    #[test]
+    fn test_async_write_yields() {
+        use futures::io::AsyncWriteExt;
+        let payload = payload();
+        let mut hasher = PieceHasher::new();
+        {
+            let waker = noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            let mut writer = AsyncWriter::new(&mut hasher);
+            let mut write = writer.write_all(&payload);
+            // Whole payload does not fit in a single poll.
+            assert!(Pin::new(&mut write).poll(&mut cx).is_pending());
+            block_on(write).unwrap();
+        }
+
+        assert_eq!(
+            hasher.multihash(),
+            PieceHasher::from(&payload[..]).multihash()
+        );
+    }
+
+    #[test]
+    fn test_async_write() {
+        use futures::io::AsyncWriteExt;
+        let payload = payload();
+        let mut hasher = PieceHasher::new();
+        block_on(AsyncWriter::new(&mut hasher).write_all(&payload)).unwrap();
+
+        assert_eq!(
+            hasher.multihash(),
+            PieceHasher::from(&payload[..]).multihash()
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_tokio_write() {
+        use tokio::io::AsyncWriteExt;
+        let payload = payload();
+        let mut hasher = PieceHasher::new();
+        block_on(AsyncWriter::new(&mut hasher).write_all(&payload)).unwrap();
+
+        assert_eq!(
+            hasher.multihash(),
+            PieceHasher::from(&payload[..]).multihash()
+        );
+    }
+}
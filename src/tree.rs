@@ -1,5 +1,12 @@
 use crate::constant::NODE_SIZE;
 use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+
+/// Size of the SHA-256 block, which is exactly the size of a node pair.
+pub(crate) const BLOCK_SIZE: usize = 2 * NODE_SIZE;
+
+/// Number of independent node pairs hashed together by the portable kernel.
+pub(crate) const LANES: usize = 8;
 
 /**
  * Represents merkle tree node.
@@ -11,7 +18,7 @@ impl MerkleTreeNode {
     pub fn new(payload: &[u8]) -> Self {
         MerkleTreeNode(truncated_hash(payload))
     }
-    pub fn join(self: &Self, right: &MerkleTreeNode) -> Self {
+    pub fn join(&self, right: &MerkleTreeNode) -> Self {
         compute_node(self, right)
     }
     pub fn empty() -> Self {
@@ -27,6 +34,12 @@ impl From<[u8; NODE_SIZE]> for MerkleTreeNode {
 
 // Function to compute the truncated hash of a payload
 pub fn truncated_hash(payload: &[u8]) -> [u8; NODE_SIZE] {
+    if payload.len() == BLOCK_SIZE {
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(payload);
+        return hash_block(&block);
+    }
+
     let mut sha256 = Sha256::new();
     sha256.update(payload);
     let mut digest = sha256.finalize().into();
@@ -36,11 +49,43 @@ pub fn truncated_hash(payload: &[u8]) -> [u8; NODE_SIZE] {
 
 // Function to compute a Merkle tree node from left and right nodes
 pub fn compute_node(left: &MerkleTreeNode, right: &MerkleTreeNode) -> MerkleTreeNode {
-    let mut payload = vec![0u8; left.0.len() + right.0.len()];
-    payload[..left.0.len()].copy_from_slice(&left.0);
-    payload[left.0.len()..].copy_from_slice(&right.0);
+    let mut block = [0u8; BLOCK_SIZE];
+    block[..NODE_SIZE].copy_from_slice(&left.0);
+    block[NODE_SIZE..].copy_from_slice(&right.0);
+
+    MerkleTreeNode(hash_block(&block))
+}
+
+/// Computes parent nodes for the node pairs of a layer, writing them into the
+/// `parents` which must be half the size of the `nodes`.
+pub(crate) fn compute_layer(nodes: &[MerkleTreeNode], parents: &mut [MerkleTreeNode]) {
+    assert_eq!(nodes.len(), parents.len() * 2);
+
+    let mut blocks = [[0u8; BLOCK_SIZE]; LANES];
+    let mut digests = [[0u8; NODE_SIZE]; LANES];
+    for (pairs, parents) in nodes.chunks(LANES * 2).zip(parents.chunks_mut(LANES)) {
+        for (block, pair) in blocks.iter_mut().zip(pairs.chunks(2)) {
+            block[..NODE_SIZE].copy_from_slice(&pair[0].0);
+            block[NODE_SIZE..].copy_from_slice(&pair[1].0);
+        }
+        hash_blocks(&blocks[..parents.len()], &mut digests[..parents.len()]);
+        for (parent, digest) in parents.iter_mut().zip(digests.iter()) {
+            *parent = MerkleTreeNode(*digest);
+        }
+    }
+}
+
+/// Computes truncated hashes of the given 64 byte blocks.
+pub(crate) fn hash_blocks(blocks: &[[u8; BLOCK_SIZE]], output: &mut [[u8; NODE_SIZE]]) {
+    assert_eq!(blocks.len(), output.len());
 
-    MerkleTreeNode(truncated_hash(&payload))
+    if has_sha_extensions() {
+        for (block, digest) in blocks.iter().zip(output.iter_mut()) {
+            *digest = hash_block(block);
+        }
+    } else {
+        hash_lanes(blocks, output);
+    }
 }
 
 pub fn empty_node() -> MerkleTreeNode {
@@ -52,3 +97,328 @@ pub fn empty_node() -> MerkleTreeNode {
 pub fn truncate(node: &mut [u8; NODE_SIZE]) {
     node[NODE_SIZE - 1] &= 0b00111111;
 }
+
+/// SHA-256 padding of a 64 byte message, which is the whole second block.
+const PADDING_BLOCK: [u8; BLOCK_SIZE] = {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[0] = 0x80;
+    // Message length in bits (512) as big endian u64
+    block[BLOCK_SIZE - 2] = 0x02;
+    block
+};
+
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Round constants added to the message schedule of the `PADDING_BLOCK`.
+/// Since second block is the same for every node, its schedule is computed
+/// at compile time.
+const PADDING_SCHEDULE: [u32; 64] = schedule(&PADDING_BLOCK);
+
+/// Same as `compute_node` but usable in `const` context, which is used to
+/// precompute tables at compile time.
+pub(crate) const fn compute_node_const(
+    left: &MerkleTreeNode,
+    right: &MerkleTreeNode,
+) -> MerkleTreeNode {
+    let mut block = [0u8; BLOCK_SIZE];
+    let mut i = 0;
+    while i < NODE_SIZE {
+        block[i] = left.0[i];
+        block[NODE_SIZE + i] = right.0[i];
+        i += 1;
+    }
+
+    let state = compress(IV, &schedule(&block));
+    let state = compress(state, &PADDING_SCHEDULE);
+
+    let mut digest = [0u8; NODE_SIZE];
+    let mut i = 0;
+    while i < 8 {
+        let bytes = state[i].to_be_bytes();
+        digest[4 * i] = bytes[0];
+        digest[4 * i + 1] = bytes[1];
+        digest[4 * i + 2] = bytes[2];
+        digest[4 * i + 3] = bytes[3];
+        i += 1;
+    }
+    digest[NODE_SIZE - 1] &= 0b00111111;
+
+    MerkleTreeNode(digest)
+}
+
+// Message schedule of the block with the round constants already added.
+const fn schedule(block: &[u8; BLOCK_SIZE]) -> [u32; 64] {
+    let mut w = [0u32; 64];
+    let mut t = 0;
+    while t < 16 {
+        w[t] = u32::from_be_bytes([
+            block[4 * t],
+            block[4 * t + 1],
+            block[4 * t + 2],
+            block[4 * t + 3],
+        ]);
+        t += 1;
+    }
+    while t < 64 {
+        w[t] = small_sigma1(w[t - 2])
+            .wrapping_add(w[t - 7])
+            .wrapping_add(small_sigma0(w[t - 15]))
+            .wrapping_add(w[t - 16]);
+        t += 1;
+    }
+
+    let mut t = 0;
+    while t < 64 {
+        w[t] = w[t].wrapping_add(K[t]);
+        t += 1;
+    }
+    w
+}
+
+// Scalar `const` version of `compress_lanes`, both share the round functions
+// below and the `schedule`.
+const fn compress(state: [u32; 8], schedule: &[u32; 64]) -> [u32; 8] {
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+    let mut t = 0;
+    while t < 64 {
+        let t1 = h
+            .wrapping_add(big_sigma1(e))
+            .wrapping_add(ch(e, f, g))
+            .wrapping_add(schedule[t]);
+        let t2 = big_sigma0(a).wrapping_add(maj(a, b, c));
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+        t += 1;
+    }
+
+    [
+        state[0].wrapping_add(a),
+        state[1].wrapping_add(b),
+        state[2].wrapping_add(c),
+        state[3].wrapping_add(d),
+        state[4].wrapping_add(e),
+        state[5].wrapping_add(f),
+        state[6].wrapping_add(g),
+        state[7].wrapping_add(h),
+    ]
+}
+
+#[inline(always)]
+const fn big_sigma0(x: u32) -> u32 {
+    x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22)
+}
+
+#[inline(always)]
+const fn big_sigma1(x: u32) -> u32 {
+    x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25)
+}
+
+#[inline(always)]
+const fn ch(x: u32, y: u32, z: u32) -> u32 {
+    (x & y) ^ (!x & z)
+}
+
+#[inline(always)]
+const fn maj(x: u32, y: u32, z: u32) -> u32 {
+    (x & y) ^ (x & z) ^ (y & z)
+}
+
+#[inline(always)]
+const fn small_sigma0(x: u32) -> u32 {
+    x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3)
+}
+
+#[inline(always)]
+const fn small_sigma1(x: u32) -> u32 {
+    x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10)
+}
+
+/// Whether CPU has SHA extensions used by `sha2`, in which case its
+/// compression function outperforms the portable multi-lane kernel.
+fn has_sha_extensions() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::is_x86_feature_detected!("sha") && std::is_x86_feature_detected!("sse4.1")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
+
+// Hashes a single block followed by the `PADDING_BLOCK`, avoiding hasher
+// buffering and finalization.
+fn hash_block(block: &[u8; BLOCK_SIZE]) -> [u8; NODE_SIZE] {
+    let mut digest = [0u8; NODE_SIZE];
+    if has_sha_extensions() {
+        let mut state = IV;
+        sha2::compress256(&mut state, &[(*block).into(), PADDING_BLOCK.into()]);
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        truncate(&mut digest);
+    } else {
+        compress_blocks::<1>(&[*block], std::slice::from_mut(&mut digest));
+    }
+    digest
+}
+
+/// Portable kernel hashing `LANES` blocks at a time, remaining blocks are
+/// hashed one by one.
+fn hash_lanes(blocks: &[[u8; BLOCK_SIZE]], output: &mut [[u8; NODE_SIZE]]) {
+    let chunks = blocks.chunks_exact(LANES);
+    let remainder = chunks.remainder();
+    let mut outputs = output.chunks_exact_mut(LANES);
+    for (blocks, output) in chunks.zip(&mut outputs) {
+        compress_blocks::<LANES>(blocks.try_into().unwrap(), output);
+    }
+    for (block, digest) in remainder.iter().zip(outputs.into_remainder()) {
+        compress_blocks::<1>(&[*block], std::slice::from_mut(digest));
+    }
+}
+
+/// Hashes `N` independent blocks, each followed by the `PADDING_BLOCK`. Every
+/// state word is kept as an array across lanes so that compiler can vectorize
+/// the rounds.
+#[inline(always)]
+fn compress_blocks<const N: usize>(blocks: &[[u8; BLOCK_SIZE]; N], output: &mut [[u8; NODE_SIZE]]) {
+    // Message schedule of the first block for every lane.
+    let mut w = [[0u32; 64]; N];
+    for (schedule, block) in w.iter_mut().zip(blocks.iter()) {
+        *schedule = self::schedule(block);
+    }
+
+    let mut state = [[0u32; N]; 8];
+    for (word, iv) in state.iter_mut().zip(IV.iter()) {
+        *word = [*iv; N];
+    }
+
+    // First block
+    compress_lanes(&mut state, |t, lane| w[lane][t]);
+    // Second block with the precomputed schedule
+    compress_lanes(&mut state, |t, _| PADDING_SCHEDULE[t]);
+
+    for (lane, digest) in output.iter_mut().enumerate() {
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(state.iter()) {
+            bytes.copy_from_slice(&word[lane].to_be_bytes());
+        }
+        truncate(digest);
+    }
+}
+
+// Runs 64 rounds of SHA-256 over all lanes where `schedule` returns message
+// word for the round and lane with the round constant already added.
+#[inline(always)]
+fn compress_lanes<const N: usize, F: Fn(usize, usize) -> u32>(
+    state: &mut [[u32; N]; 8],
+    schedule: F,
+) {
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for t in 0..64 {
+        for lane in 0..N {
+            let t1 = h[lane]
+                .wrapping_add(big_sigma1(e[lane]))
+                .wrapping_add(ch(e[lane], f[lane], g[lane]))
+                .wrapping_add(schedule(t, lane));
+            let t2 = big_sigma0(a[lane]).wrapping_add(maj(a[lane], b[lane], c[lane]));
+
+            h[lane] = g[lane];
+            g[lane] = f[lane];
+            f[lane] = e[lane];
+            e[lane] = d[lane].wrapping_add(t1);
+            d[lane] = c[lane];
+            c[lane] = b[lane];
+            b[lane] = a[lane];
+            a[lane] = t1.wrapping_add(t2);
+        }
+    }
+
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+        for (word, value) in word.iter_mut().zip(value.iter()) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constant::NODE_SIZE;
+    use crate::tree::{
+        compute_layer, compute_node, hash_blocks, hash_lanes, MerkleTreeNode, BLOCK_SIZE,
+    };
+    use sha2::{Digest, Sha256};
+
+    fn reference(block: &[u8]) -> [u8; NODE_SIZE] {
+        let mut digest: [u8; NODE_SIZE] = Sha256::digest(block).into();
+        digest[NODE_SIZE - 1] &= 0b00111111;
+        digest
+    }
+
+    fn blocks(count: usize) -> Vec<[u8; BLOCK_SIZE]> {
+        (0..count)
+            .map(|n| {
+                let mut block = [0u8; BLOCK_SIZE];
+                for (i, byte) in block.iter_mut().enumerate() {
+                    *byte = (n * 31 + i * 7) as u8;
+                }
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_kernels_match_sha256() {
+        for count in [0, 1, 2, 7, 8, 9, 17] {
+            let blocks = blocks(count);
+            let expected: Vec<_> = blocks.iter().map(|block| reference(block)).collect();
+
+            let mut output = vec![[0u8; NODE_SIZE]; count];
+            hash_blocks(&blocks, &mut output);
+            assert_eq!(output, expected);
+
+            let mut output = vec![[0u8; NODE_SIZE]; count];
+            hash_lanes(&blocks, &mut output);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_compute_layer() {
+        let nodes: Vec<_> = blocks(11)
+            .iter()
+            .map(|block| MerkleTreeNode(reference(block)))
+            .collect();
+        let nodes = &nodes[..10];
+
+        let mut parents = vec![MerkleTreeNode::empty(); 5];
+        compute_layer(nodes, &mut parents);
+
+        for (parent, pair) in parents.iter().zip(nodes.chunks(2)) {
+            assert_eq!(*parent, compute_node(&pair[0], &pair[1]));
+            let mut block = [0u8; BLOCK_SIZE];
+            block[..NODE_SIZE].copy_from_slice(&pair[0].0);
+            block[NODE_SIZE..].copy_from_slice(&pair[1].0);
+            assert_eq!(parent.0, reference(&block));
+        }
+    }
+}
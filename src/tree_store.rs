@@ -0,0 +1,427 @@
+use crate::constant::NODE_SIZE;
+use crate::error::PieceHasherError;
+use crate::hasher::PieceHasher;
+use crate::piece::Piece;
+use crate::piece_tree::PieceTree;
+use crate::proof::ProofData;
+use crate::size::PieceSizes;
+use crate::tree::{compute_layer, MerkleTreeNode};
+use crate::zero_comm;
+use multihash::Multihash;
+use std::convert::{TryFrom, TryInto};
+use std::io;
+
+// Number of parent nodes checked at a time by `TreeStore::validate`.
+const VALIDATE_CHUNK: usize = 1024;
+
+// Number of nodes `TreeWriter` buffers for each level before writing them.
+const WRITE_CHUNK: usize = 1024;
+
+/// Piece tree stored in the flat `tree_d` layout of rust-fil-proofs, where
+/// layers follow each other starting from the leaves and ending with the
+/// root. Nodes are read directly from the backing bytes, so that large trees
+/// can be served from a memory-mapped file.
+pub struct TreeStore<B> {
+    bytes: B,
+    height: usize,
+}
+
+impl<B: AsRef<[u8]>> TreeStore<B> {
+    /// Wraps bytes of a `tree_d` file, which must hold a complete tree.
+    pub fn new(bytes: B) -> Result<Self, PieceHasherError> {
+        let length = bytes.as_ref().len();
+        // Complete binary tree with 2^h leaves has 2^(h+1) - 1 nodes.
+        let nodes = (length / NODE_SIZE) as u64 + 1;
+        if length % NODE_SIZE != 0 || !nodes.is_power_of_two() || nodes < 2 {
+            return Err(PieceHasherError::InvalidTree(format!(
+                "{} bytes is not a size of a complete tree",
+                length
+            )));
+        }
+
+        let height = nodes.trailing_zeros() as usize - 1;
+        if height >= zero_comm::MAX_LEVEL {
+            return Err(PieceHasherError::LevelOutOfRange {
+                level: height,
+                max: zero_comm::MAX_LEVEL - 1,
+            });
+        }
+
+        Ok(TreeStore { bytes, height })
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn root(&self) -> MerkleTreeNode {
+        self.node(self.height, 0).unwrap()
+    }
+
+    /// Node at the given level and index within that level.
+    pub fn node(&self, level: usize, index: u64) -> Option<MerkleTreeNode> {
+        if level > self.height || index >> (self.height - level) != 0 {
+            return None;
+        }
+        let offset = usize::try_from(self.offset(level) + index).ok()? * NODE_SIZE;
+        let bytes = &self.bytes.as_ref()[offset..offset + NODE_SIZE];
+        Some(MerkleTreeNode(bytes.try_into().unwrap()))
+    }
+
+    /// Produces inclusion proof for the node at the given level and index.
+    pub fn prove(&self, level: usize, index: u64) -> Result<ProofData, PieceHasherError> {
+        if self.node(level, index).is_none() {
+            return Err(PieceHasherError::NodeOutOfRange { level, index });
+        }
+
+        let path = (level..self.height)
+            .map(|at| self.node(at, (index >> (at - level)) ^ 1).unwrap())
+            .collect();
+        Ok(ProofData::new(index, path))
+    }
+
+    /// Checks that the stored tree has the root and height of the piece with
+    /// the given multihash and returns that piece.
+    pub fn verify(&self, multihash: &Multihash) -> Result<Piece, PieceHasherError> {
+        let piece = Piece::try_from(multihash)?;
+        if piece.height() != self.height || piece.root() != self.root() {
+            return Err(PieceHasherError::InvalidTree(String::from(
+                "Root does not match the piece",
+            )));
+        }
+        Ok(piece)
+    }
+
+    /// Recomputes every parent node from its children to make sure stored
+    /// tree is consistent, which requires hashing the whole tree.
+    pub fn validate(&self) -> Result<(), PieceHasherError> {
+        let mut children = Vec::with_capacity(VALIDATE_CHUNK * 2);
+        let mut parents = vec![MerkleTreeNode::empty(); VALIDATE_CHUNK];
+        for level in 1..=self.height {
+            let width = 1u64 << (self.height - level);
+            let mut index = 0;
+            while index < width {
+                let count = (width - index).min(VALIDATE_CHUNK as u64) as usize;
+                children.clear();
+                for child in index * 2..(index + count as u64) * 2 {
+                    children.push(self.node(level - 1, child).unwrap());
+                }
+                compute_layer(&children, &mut parents[..count]);
+
+                for (offset, parent) in parents[..count].iter().enumerate() {
+                    let position = index + offset as u64;
+                    if self.node(level, position).unwrap() != *parent {
+                        return Err(PieceHasherError::InvalidTree(format!(
+                            "Node {} of level {} does not match its children",
+                            position, level
+                        )));
+                    }
+                }
+                index += count as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the backing bytes.
+    pub fn into_inner(self) -> B {
+        self.bytes
+    }
+
+    // Number of nodes in the layers below the given level.
+    fn offset(&self, level: usize) -> u64 {
+        layer_offset(self.height, level)
+    }
+}
+
+// Number of nodes in the layers below the given level of the `tree_d` of the
+// given height.
+fn layer_offset(height: usize, level: usize) -> u64 {
+    (1 << (height + 1)) - (1 << (height + 1 - level))
+}
+
+impl TreeStore<Vec<u8>> {
+    /// Writes all the layers of the tree in the `tree_d` layout.
+    pub fn write<W: io::Write>(tree: &PieceTree, mut writer: W) -> io::Result<()> {
+        for level in 0..=tree.height() {
+            for node in tree.layer(level).unwrap() {
+                writer.write_all(&node.0)?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Hashes the payload and writes its tree in the `tree_d` layout as nodes
+/// are computed, so that unlike `TreeStore::write` the tree is never held in
+/// memory. Layers of the tree follow each other, which is why the height and
+/// with it the payload size has to be known upfront and the writer has to be
+/// seekable.
+pub struct TreeWriter<W> {
+    hasher: PieceHasher,
+    writer: W,
+    payload_size: u64,
+    height: usize,
+    // Number of nodes of every level written so far.
+    written: Vec<u64>,
+}
+
+impl<W: io::Write + io::Seek> TreeWriter<W> {
+    /// Creates a writer for the tree of the payload of the given size.
+    pub fn new(writer: W, payload_size: u64) -> Result<Self, PieceHasherError> {
+        let height = PieceSizes::new(payload_size)?.height();
+        let mut hasher = PieceHasher::new();
+        hasher.retained = Some(vec![Vec::new()]);
+        Ok(TreeWriter {
+            hasher,
+            writer,
+            payload_size,
+            height,
+            written: vec![0; height + 1],
+        })
+    }
+
+    pub fn try_update(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.hasher.bytes_written + bytes.len() as u64 > self.payload_size {
+            return Err(self.size_mismatch(bytes.len() as u64).into());
+        }
+        self.hasher.try_update(bytes)?;
+
+        // Nodes of each level are computed in order, so they are written
+        // once enough of them accumulate.
+        let mut layers = self.hasher.retained.take().unwrap();
+        for (level, layer) in layers.iter_mut().enumerate() {
+            if layer.len() >= WRITE_CHUNK {
+                self.write_nodes(level, layer)?;
+                layer.clear();
+            }
+        }
+        self.hasher.retained = Some(layers);
+        Ok(())
+    }
+
+    /// Writes the remaining nodes of the tree, including the ones covering
+    /// the zero padding, and returns the piece along with the writer. All
+    /// the payload bytes must have been written.
+    pub fn finish(mut self) -> io::Result<(Piece, W)> {
+        if self.hasher.bytes_written != self.payload_size {
+            return Err(self.size_mismatch(0).into());
+        }
+
+        let layers = self.hasher.retained.take().unwrap();
+        for (level, layer) in layers.iter().enumerate() {
+            self.write_nodes(level, layer)?;
+        }
+
+        // Nodes covering the partially written (or last) quad are only
+        // computed along with the root.
+        let mut nodes = vec![];
+        self.hasher
+            .root_with(|level, index, node| nodes.push((level, index, *node)));
+        for (level, index, node) in nodes {
+            if index >= self.written[level] {
+                self.written[level] = index;
+                self.write_nodes(level, &[node])?;
+            }
+        }
+
+        // Rest of every layer only covers the zero padding.
+        for level in 0..=self.height {
+            let zeros = vec![zero_comm::from_level(level).unwrap(); WRITE_CHUNK];
+            let width = 1u64 << (self.height - level);
+            while self.written[level] < width {
+                let count = (width - self.written[level]).min(WRITE_CHUNK as u64) as usize;
+                self.write_nodes(level, &zeros[..count])?;
+            }
+        }
+        self.writer.flush()?;
+
+        Ok((self.hasher.piece(), self.writer))
+    }
+
+    // Writes nodes following the ones already written to the level.
+    fn write_nodes(&mut self, level: usize, nodes: &[MerkleTreeNode]) -> io::Result<()> {
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        let offset = (layer_offset(self.height, level) + self.written[level]) * NODE_SIZE as u64;
+        self.writer.seek(io::SeekFrom::Start(offset))?;
+        for node in nodes {
+            self.writer.write_all(&node.0)?;
+        }
+        self.written[level] += nodes.len() as u64;
+        Ok(())
+    }
+
+    fn size_mismatch(&self, length: u64) -> PieceHasherError {
+        PieceHasherError::InvalidTree(format!(
+            "Expected {} payload bytes, got {}",
+            self.payload_size,
+            self.hasher.bytes_written + length
+        ))
+    }
+}
+
+impl<W: io::Write + io::Seek> io::Write for TreeWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.try_update(bytes)?;
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl From<&PieceTree> for TreeStore<Vec<u8>> {
+    fn from(tree: &PieceTree) -> Self {
+        let mut bytes = Vec::with_capacity(((2 << tree.height()) - 1) * NODE_SIZE);
+        TreeStore::write(tree, &mut bytes).unwrap();
+        TreeStore {
+            bytes,
+            height: tree.height(),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl TreeStore<memmap2::Mmap> {
+    /// Memory-maps the `tree_d` file at the given path.
+    ///
+    /// # Safety
+    ///
+    /// File must not be modified or truncated (e.g. by another process)
+    /// while it is mapped, as nodes are read directly from the mapped memory
+    /// and any change to the file is undefined behavior.
+    pub unsafe fn open<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let map = memmap2::Mmap::map(&file)?;
+        Ok(TreeStore::new(map)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TreeStore, TreeWriter};
+    use crate::error::PieceHasherError;
+    use crate::piece_tree::tests::{payload, tree};
+    use crate::tree::MerkleTreeNode;
+    use std::convert::TryInto;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn test_layout() {
+        let (piece, tree) = tree(127 * 3 + 1);
+        let mut bytes = vec![];
+        TreeStore::write(&tree, &mut bytes).unwrap();
+        assert_eq!(bytes.len(), (2 * 16 - 1) * 32);
+        assert_eq!(&bytes[..32], &tree.node(0, 0).unwrap().0);
+        assert_eq!(&bytes[16 * 32..17 * 32], &tree.node(1, 0).unwrap().0);
+        assert_eq!(&bytes[bytes.len() - 32..], &piece.root().0);
+
+        let store = TreeStore::new(bytes).unwrap();
+        assert_eq!(store.height(), tree.height());
+        assert_eq!(store.root(), tree.root());
+        for level in 0..=tree.height() {
+            for (index, node) in tree.layer(level).unwrap().iter().enumerate() {
+                assert_eq!(store.node(level, index as u64), Some(*node));
+                assert_eq!(
+                    store.prove(level, index as u64),
+                    tree.prove(level, index as u64)
+                );
+            }
+        }
+        assert_eq!(store.node(1, 8), None);
+        assert_eq!(store.node(5, 0), None);
+        assert!(store.prove(0, 16).is_err());
+
+        assert_eq!(store.verify(&piece.multihash()), Ok(piece));
+        store.validate().unwrap();
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        let (piece, tree) = tree(127 * 5);
+        let store = TreeStore::from(&tree);
+        assert_eq!(store.verify(&piece.multihash()), Ok(piece));
+
+        let (other, _) = self::tree(127 * 5 - 1);
+        assert!(store.verify(&other.multihash()).is_err());
+        let (smaller, _) = self::tree(127 * 2);
+        assert!(store.verify(&smaller.multihash()).is_err());
+        let sha256 = multihash::Multihash::wrap(0x12, &[0u8; 32]).unwrap();
+        assert!(store.verify(&sha256).is_err());
+
+        let mut bytes = store.into_inner();
+        bytes[40] ^= 1;
+        let corrupted = TreeStore::new(bytes.clone()).unwrap();
+        assert_eq!(
+            corrupted.validate(),
+            Err(PieceHasherError::InvalidTree(String::from(
+                "Node 0 of level 1 does not match its children"
+            )))
+        );
+
+        assert!(TreeStore::new(&bytes[..bytes.len() - 32]).is_err());
+        assert!(TreeStore::new(&bytes[..bytes.len() - 1]).is_err());
+        assert!(TreeStore::new(&bytes[..0]).is_err());
+        assert_eq!(
+            TreeStore::new(&bytes[..32]).unwrap().root(),
+            MerkleTreeNode(bytes[..32].try_into().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_writer() {
+        for size in [0, 1, 127, 127 * 3 + 1, 127 * 4, 127 * 2048 + 5] {
+            let bytes = payload(size);
+            let (piece, tree) = tree(size);
+            let mut expected = vec![];
+            TreeStore::write(&tree, &mut expected).unwrap();
+
+            let mut writer = TreeWriter::new(Cursor::new(vec![]), size as u64).unwrap();
+            for chunk in bytes.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+            }
+            let (result, cursor) = writer.finish().unwrap();
+            assert_eq!(result, piece);
+            assert!(cursor.into_inner() == expected, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_writer_rejects_size_mismatch() {
+        let mut writer = TreeWriter::new(Cursor::new(vec![]), 100).unwrap();
+        writer.try_update(&[1u8; 60]).unwrap();
+        let error = writer.try_update(&[1u8; 60]).unwrap_err();
+        assert_eq!(
+            error.into_inner().unwrap().to_string(),
+            "Invalid tree: Expected 100 payload bytes, got 120"
+        );
+
+        let error = writer.finish().unwrap_err();
+        assert_eq!(
+            error.into_inner().unwrap().to_string(),
+            "Invalid tree: Expected 100 payload bytes, got 60"
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_open() {
+        let (piece, tree) = tree(127 * 40 + 3);
+        let bytes = payload(127 * 40 + 3);
+        let path = std::env::temp_dir().join(format!("tree_d-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let mut writer =
+            TreeWriter::new(std::io::BufWriter::new(file), bytes.len() as u64).unwrap();
+        writer.write_all(&bytes).unwrap();
+        writer.finish().unwrap();
+
+        let store = unsafe { TreeStore::open(&path) }.unwrap();
+        assert_eq!(store.verify(&piece.multihash()), Ok(piece));
+        assert_eq!(store.prove(0, 17), tree.prove(0, 17));
+        store.validate().unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
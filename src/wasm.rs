@@ -0,0 +1,256 @@
+use crate::error::PieceHasherError;
+use crate::hasher::PieceHasher;
+use crate::piece::Piece;
+use crate::size::{PaddedPieceSize, PieceSizes, UnpaddedPieceSize};
+use js_sys::Error;
+use multihash::Multihash;
+use multihash_derive::Hasher;
+use std::convert::TryFrom;
+use wasm_bindgen::prelude::*;
+
+type PieceMultihasher = PieceHasher;
+
+// Errors are only turned into JS values at the wasm boundary.
+impl From<PieceHasherError> for JsValue {
+    fn from(error: PieceHasherError) -> Self {
+        Error::new(&error.to_string()).into()
+    }
+}
+
+#[wasm_bindgen(inspectable)]
+impl PieceHasher {
+    /// Creates a new hasher
+    #[wasm_bindgen(constructor)]
+    pub fn create() -> PieceMultihasher {
+        PieceHasher::default()
+    }
+
+    #[wasm_bindgen]
+    pub fn count(&self) -> u64 {
+        self.bytes_written
+    }
+    /// Resets the hasher state
+    #[wasm_bindgen]
+    pub fn reset(&mut self) {
+        Hasher::reset(self);
+    }
+
+    #[wasm_bindgen]
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        Ok(PieceHasher::try_update(self, bytes)?)
+    }
+
+    #[wasm_bindgen(js_name = digestInto)]
+    pub fn read(
+        &self,
+        target: &mut [u8],
+        offset: Option<usize>,
+        use_prefix: Option<bool>,
+    ) -> Result<usize, JsValue> {
+        Ok(self.digest_into(target, offset.unwrap_or(0), use_prefix.unwrap_or(true))?)
+    }
+
+    #[wasm_bindgen(js_name = digestByteLength)]
+    pub fn digest_byte_length(&self) -> usize {
+        self.digest_size()
+    }
+
+    #[wasm_bindgen(js_name = multihashByteLength)]
+    pub fn multihash_byte_length(&self) -> usize {
+        self.multihash_size()
+    }
+
+    /// Returns the piece for all the bytes written, reporting the remaining
+    /// nodes to the observer and dropping it.
+    #[wasm_bindgen(js_name = finish)]
+    pub fn finish_piece(&mut self) -> Piece {
+        self.finish()
+    }
+
+    /// Serializes hasher state so it can be resumed with `restore`.
+    #[wasm_bindgen(js_name = checkpoint)]
+    pub fn to_checkpoint(&self) -> Vec<u8> {
+        PieceHasher::checkpoint(self)
+    }
+
+    /// Creates a hasher from the state serialized by `checkpoint`.
+    #[wasm_bindgen(js_name = restore)]
+    pub fn from_checkpoint(bytes: &[u8]) -> Result<PieceMultihasher, JsValue> {
+        Ok(PieceHasher::restore(bytes)?)
+    }
+}
+
+#[wasm_bindgen]
+pub fn create() -> PieceMultihasher {
+    PieceHasher::default()
+}
+
+/// Creates a hasher from the state serialized by `checkpoint`.
+#[wasm_bindgen]
+pub fn restore(checkpoint: &[u8]) -> Result<PieceMultihasher, JsValue> {
+    Ok(PieceHasher::restore(checkpoint)?)
+}
+
+// JS values can not be shared across threads, so observers are only available
+// in wasm where the hasher does not need to be thread safe.
+#[cfg(target_arch = "wasm32")]
+mod observer {
+    use super::PieceMultihasher;
+    use crate::hasher::{NodeObserver, PieceHasher};
+    use crate::tree::MerkleTreeNode;
+    use js_sys::{Function, Uint8Array};
+    use wasm_bindgen::prelude::*;
+
+    // Calls JS function with the level, index and bytes of every observed
+    // node. Errors thrown by the function are ignored as hashing can not be
+    // aborted.
+    struct JsObserver(Function);
+
+    impl NodeObserver for JsObserver {
+        fn observe(&mut self, level: usize, index: u64, node: &MerkleTreeNode) {
+            let _ = self.0.call3(
+                &JsValue::NULL,
+                &JsValue::from(level as u32),
+                &JsValue::from(index as f64),
+                &Uint8Array::from(&node.0[..]),
+            );
+        }
+    }
+
+    /// Creates a hasher calling `observer(level, index, node)` for every node
+    /// of the tree as it is computed.
+    #[wasm_bindgen(js_name = withObserver)]
+    pub fn with_observer(observer: Function) -> PieceMultihasher {
+        PieceHasher::with_observer(JsObserver(observer))
+    }
+}
+
+#[wasm_bindgen]
+impl Piece {
+    /// Decodes the piece from the bytes of its multihash.
+    #[wasm_bindgen(js_name = fromMultihash)]
+    pub fn from_multihash_bytes(bytes: &[u8]) -> Result<Piece, JsValue> {
+        let multihash = Multihash::from_bytes(bytes)
+            .map_err(|error| PieceHasherError::InvalidPiece(error.to_string()))?;
+        Ok(Piece::try_from(&multihash)?)
+    }
+
+    /// Decodes the piece from its string encoded CID e.g. `bafkzcib...`.
+    #[wasm_bindgen(js_name = fromLink)]
+    pub fn from_link_string(link: &str) -> Result<Piece, JsValue> {
+        let link = cid::Cid::try_from(link)
+            .map_err(|error| PieceHasherError::InvalidPiece(error.to_string()))?;
+        Ok(Piece::try_from(&link)?)
+    }
+
+    /// Creates the piece from the string encoded PieceCIDv1 (`baga...`),
+    /// padded piece size and the size of the payload it holds.
+    #[wasm_bindgen(js_name = fromLinkV1)]
+    pub fn from_link_v1_string(link: &str, size: u64, payload_size: u64) -> Result<Piece, JsValue> {
+        let link = cid::Cid::try_from(link)
+            .map_err(|error| PieceHasherError::InvalidPiece(error.to_string()))?;
+        Ok(Piece::from_v1(&link, size, payload_size)?)
+    }
+
+    #[wasm_bindgen(getter = height)]
+    pub fn tree_height(&self) -> u32 {
+        self.height() as u32
+    }
+
+    #[wasm_bindgen(getter = root)]
+    pub fn root_bytes(&self) -> Vec<u8> {
+        self.root().0.to_vec()
+    }
+
+    /// Piece size in bytes.
+    #[wasm_bindgen(getter = size)]
+    pub fn piece_size(&self) -> u64 {
+        self.size()
+    }
+
+    #[wasm_bindgen(getter = paddingSize)]
+    pub fn padding_byte_length(&self) -> u64 {
+        self.padding_size()
+    }
+
+    #[wasm_bindgen(getter = payloadSize)]
+    pub fn payload_byte_length(&self) -> u64 {
+        self.payload_size()
+    }
+
+    /// Bytes of the piece multihash.
+    #[wasm_bindgen(js_name = multihash)]
+    pub fn multihash_bytes(&self) -> Vec<u8> {
+        self.multihash().to_bytes()
+    }
+
+    /// String encoded piece CID.
+    #[wasm_bindgen(js_name = link)]
+    pub fn link_string(&self) -> String {
+        self.link().to_string()
+    }
+
+    /// String encoded PieceCIDv1, padded piece size is available as `size`.
+    #[wasm_bindgen(js_name = linkV1)]
+    pub fn link_v1_string(&self) -> String {
+        self.link_v1().0.to_string()
+    }
+}
+
+#[wasm_bindgen]
+impl PieceSizes {
+    /// Calculates sizes of the piece for the payload of the given size.
+    #[wasm_bindgen(js_name = fromPayloadSize)]
+    pub fn from_payload_size(payload_size: u64) -> Result<PieceSizes, JsValue> {
+        Ok(PieceSizes::new(payload_size)?)
+    }
+
+    #[wasm_bindgen(getter = height)]
+    pub fn tree_height(&self) -> u32 {
+        self.height() as u32
+    }
+
+    #[wasm_bindgen(getter = payloadSize)]
+    pub fn payload_byte_length(&self) -> u64 {
+        self.payload_size()
+    }
+
+    #[wasm_bindgen(getter = paddingSize)]
+    pub fn padding_byte_length(&self) -> u64 {
+        self.padding_size()
+    }
+
+    #[wasm_bindgen(getter = unpaddedSize)]
+    pub fn unpadded_byte_length(&self) -> u64 {
+        self.unpadded_size().bytes()
+    }
+
+    #[wasm_bindgen(getter = paddedSize)]
+    pub fn padded_byte_length(&self) -> u64 {
+        self.padded_size().bytes()
+    }
+
+    #[wasm_bindgen(getter = digestByteLength)]
+    pub fn digest_byte_length(&self) -> usize {
+        self.digest_size()
+    }
+
+    #[wasm_bindgen(getter = multihashByteLength)]
+    pub fn multihash_byte_length(&self) -> usize {
+        self.multihash_size()
+    }
+}
+
+/// Converts unpadded piece size into the padded one, failing if it is not
+/// a valid piece size.
+#[wasm_bindgen(js_name = toPaddedPieceSize)]
+pub fn to_padded_piece_size(size: u64) -> Result<u64, JsValue> {
+    Ok(UnpaddedPieceSize::new(size)?.padded().bytes())
+}
+
+/// Converts padded piece size into the unpadded one, failing if it is not
+/// a valid piece size.
+#[wasm_bindgen(js_name = toUnpaddedPieceSize)]
+pub fn to_unpadded_piece_size(size: u64) -> Result<u64, JsValue> {
+    Ok(PaddedPieceSize::new(size)?.unpadded().bytes())
+}
@@ -1,67 +1,37 @@
 use crate::constant::NODE_SIZE;
-use crate::tree::MerkleTreeNode;
-use lazy_static::lazy_static;
-const MAX_LEVEL: usize = 64; // Define the maximum level
+use crate::error::PieceHasherError;
+use crate::tree::{compute_node_const, MerkleTreeNode};
 
-/// Lazy zero-comm buffer which fills up on demand.
+/// Number of zero commitment levels available.
+pub const MAX_LEVEL: usize = 64;
 
-struct ZeroComm {
-    bytes: Vec<u8>,
-    node: MerkleTreeNode,
-    length: usize,
-}
-
-impl ZeroComm {
-    fn new() -> Self {
-        let mut zero_comm = ZeroComm {
-            bytes: vec![0; MAX_LEVEL * NODE_SIZE],
-            node: MerkleTreeNode::empty(),
-            length: NODE_SIZE,
-        };
-
-        zero_comm.bytes[..NODE_SIZE].copy_from_slice(&zero_comm.node.0);
-
-        zero_comm
-    }
-
-    fn slice(&mut self, start: usize, end: usize) -> &[u8] {
-        while self.length < end {
-            self.node = self.node.join(&self.node);
-            self.bytes[self.length..self.length + NODE_SIZE].copy_from_slice(&self.node.0);
-            self.length += NODE_SIZE;
-        }
-
-        &self.bytes[start..end]
-    }
-}
-
-lazy_static! {
-    static ref ZERO_COMM: std::sync::Mutex<ZeroComm> = std::sync::Mutex::new(ZeroComm::new());
-}
-
-/// Access nodes by level.
-pub fn from_level(level: usize) -> Result<MerkleTreeNode, String> {
-    if level >= MAX_LEVEL {
-        let result: Result<MerkleTreeNode, String> = Err(format!(
-            "Only levels between 0 and {} inclusive are available",
-            MAX_LEVEL - 1,
-        ));
-        return result;
+/// Zero commitments for every level computed at compile time. Level 0 is the
+/// zero node and every next level is a node of two previous level nodes.
+static ZERO_COMM: [MerkleTreeNode; MAX_LEVEL] = {
+    let mut nodes = [MerkleTreeNode([0u8; NODE_SIZE]); MAX_LEVEL];
+    let mut level = 1;
+    while level < MAX_LEVEL {
+        nodes[level] = compute_node_const(&nodes[level - 1], &nodes[level - 1]);
+        level += 1;
     }
+    nodes
+};
 
-    let start = NODE_SIZE * level;
-    let end = NODE_SIZE * (level + 1);
-
-    let mut zero_comm = ZERO_COMM.lock().unwrap();
-    let mut node = [0u8; NODE_SIZE];
-    node.copy_from_slice(zero_comm.slice(start, end));
-
-    Ok(MerkleTreeNode(node))
+/// Returns the root of a tree of given level where every leaf is a zero node.
+pub fn from_level(level: usize) -> Result<MerkleTreeNode, PieceHasherError> {
+    ZERO_COMM
+        .get(level)
+        .copied()
+        .ok_or(PieceHasherError::LevelOutOfRange {
+            level,
+            max: MAX_LEVEL - 1,
+        })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::zero_comm::from_level;
+    use crate::error::PieceHasherError;
+    use crate::zero_comm::{from_level, MAX_LEVEL};
 
     #[test]
     fn test_from_level_0() {
@@ -69,9 +39,30 @@ mod tests {
         assert_eq!(node.unwrap().0, [0u8; 32]);
     }
 
+    #[test]
+    fn test_from_level_1() {
+        let node = from_level(1).unwrap();
+        assert_eq!(
+            hex::encode(node.0),
+            "f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb0b"
+        );
+    }
+
+    #[test]
+    fn test_matches_computed() {
+        let mut node = from_level(0).unwrap();
+        for level in 1..MAX_LEVEL {
+            node = node.join(&node);
+            assert_eq!(from_level(level).unwrap(), node);
+        }
+    }
+
     #[test]
     fn test_too_large() {
         let node = from_level(64);
-        assert_eq!(node.is_err(), true);
+        assert_eq!(
+            node,
+            Err(PieceHasherError::LevelOutOfRange { level: 64, max: 63 })
+        );
     }
 }